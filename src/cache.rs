@@ -0,0 +1,98 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use mime_guess::Mime;
+
+#[derive(Clone)]
+pub struct CachedFile {
+    pub body: Arc<[u8]>,
+    pub mime: Mime,
+    pub modified: Option<SystemTime>,
+}
+
+struct Entry {
+    file: CachedFile,
+    used: u64,
+}
+
+/// File bodies kept in memory, keyed by their resolved path. Once `capacity` bytes are in use the least recently used
+/// ones are evicted to make room.
+pub struct FileCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<PathBuf, Entry>,
+    // Paths by when they were last used, the first one is the next to go
+    order: BTreeMap<u64, PathBuf>,
+}
+
+impl fmt::Debug for FileCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCache")
+            .field("capacity", &self.capacity)
+            .field("size", &self.size)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+impl FileCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the entry for `path` if it still matches the file's current length and mtime, a stale one is dropped
+    pub fn get(&mut self, path: &Path, len: usize, modified: Option<SystemTime>) -> Option<CachedFile> {
+        let entry = self.entries.get(path)?;
+        if entry.file.body.len() != len || entry.file.modified != modified {
+            self.remove(path);
+            return None;
+        }
+        let used = self.touch(path.to_path_buf());
+        let entry = self.entries.get_mut(path)?;
+        self.order.remove(&entry.used);
+        entry.used = used;
+        Some(entry.file.clone())
+    }
+
+    pub fn insert(&mut self, path: PathBuf, file: CachedFile) {
+        self.remove(&path);
+        if file.body.len() > self.capacity {
+            return;
+        }
+        while self.size + file.body.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.file.body.len();
+            }
+        }
+        self.size += file.body.len();
+        let used = self.touch(path.clone());
+        self.entries.insert(path, Entry { file, used });
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.order.remove(&entry.used);
+            self.size -= entry.file.body.len();
+        }
+    }
+
+    // Marks `path` as the most recently used one
+    fn touch(&mut self, path: PathBuf) -> u64 {
+        self.tick += 1;
+        self.order.insert(self.tick, path);
+        self.tick
+    }
+}
@@ -1,13 +1,21 @@
+use std::any::Any;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::io;
 use std::io::Write as _;
-use std::io::{self, Error};
+use std::net::SocketAddr;
 use std::sync::LazyLock;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use async_compression::tokio::bufread::GzipEncoder;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, SeekFrom};
+#[cfg(all(feature = "sendfile", target_os = "linux"))]
 use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Instant};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Method {
     Get,
     Post,
@@ -20,11 +28,101 @@ pub enum Method {
     Patch,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum HttpVersion {
+    /// A simple request without a version, answered with the bare body
+    Http0_9,
+    #[default]
     Http1_0,
     Http1_1,
-    Unknown,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Head => "HEAD",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http0_9 => "HTTP/0.9",
+            HttpVersion::Http1_0 => "HTTP/1.0",
+            HttpVersion::Http1_1 => "HTTP/1.1",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// The client closed the connection before sending a full request line
+    ConnectionClosed,
+    BadRequestLine,
+    /// Method is a valid token but not one we know. Only known methods that aren't served get 501
+    UnknownMethod,
+    BadHeader,
+    /// The request line is longer than allowed, almost always because of the URI
+    UriTooLong,
+    HeadersTooLarge,
+    /// Well-formed version that we don't support, such as `HTTP/0.9` or `HTTP/2.0`
+    UnsupportedVersion,
+    /// The body is delimited by `Transfer-Encoding`, only `Content-Length` bodies can be read or skipped
+    LengthRequired,
+    /// The read timeout elapsed, `partial` is set if part of a request was already received
+    Timeout {
+        partial: bool,
+    },
+    Io(io::Error),
+}
+
+impl HttpError {
+    /// Status to answer with, `None` if the connection is unusable and nothing should be written
+    pub fn status(&self) -> Option<&'static str> {
+        match self {
+            HttpError::ConnectionClosed | HttpError::Timeout { partial: false } | HttpError::Io(_) => None,
+            HttpError::Timeout { partial: true } => Some("408 Request Timeout"),
+            HttpError::BadRequestLine | HttpError::BadHeader | HttpError::UnknownMethod => Some("400 Bad Request"),
+            HttpError::UriTooLong => Some("414 URI Too Long"),
+            HttpError::HeadersTooLarge => Some("431 Request Header Fields Too Large"),
+            HttpError::UnsupportedVersion => Some("505 HTTP Version Not Supported"),
+            HttpError::LengthRequired => Some("411 Length Required"),
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::ConnectionClosed => write!(f, "connection closed"),
+            HttpError::BadRequestLine => write!(f, "invalid request line"),
+            HttpError::UnknownMethod => write!(f, "unknown method"),
+            HttpError::BadHeader => write!(f, "invalid header line"),
+            HttpError::UriTooLong => write!(f, "request line too long"),
+            HttpError::HeadersTooLarge => write!(f, "header section too large"),
+            HttpError::UnsupportedVersion => write!(f, "unsupported http version"),
+            HttpError::LengthRequired => write!(f, "request body without content-length"),
+            HttpError::Timeout { .. } => write!(f, "timed out waiting for request"),
+            HttpError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> Self {
+        HttpError::Io(err)
+    }
 }
 
 #[derive(Debug)]
@@ -34,10 +132,51 @@ pub struct RequestLine {
     pub version: HttpVersion,
 }
 
+/// Request headers, looked up case-insensitively. Repeated headers are combined into a single comma separated value
+#[derive(Debug, Default)]
+pub struct Headers(HashMap<String, String>);
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    fn append(&mut self, name: &str, value: &str) {
+        self.0
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub line: RequestLine,
+    pub headers: Headers,
+}
+
 #[derive(Debug, Default)]
-pub struct ResponseOptions {
+pub struct ResponseOptions<'a> {
+    /// Version of the request being answered
+    pub version: HttpVersion,
     pub keep_open: bool,
     pub omit_body: bool,
+    /// Keep the connection open even after responding with a 4xx/5xx status
+    pub keep_alive_after_error: bool,
+    /// Compress text-like bodies with gzip, set when the client accepts it
+    pub gzip: bool,
+    /// Sent with every response, after the headers given to the writer
+    pub extra_headers: &'a [(&'a str, &'a str)],
+    /// Names of headers that must not be sent. Content-Length and Transfer-Encoding are never stripped since they frame
+    /// the response
+    pub strip_headers: &'a [String],
+    /// Coding the body already has, such as a precompressed file. It's declared as is and never compressed again
+    pub content_encoding: Option<&'a str>,
+    /// Value of the `Server` header, not sent when `None`
+    pub server: Option<&'a str>,
 }
 
 static METHODS_HASH: LazyLock<HashMap<&'static [u8], Method>> = LazyLock::new(|| {
@@ -54,85 +193,437 @@ static METHODS_HASH: LazyLock<HashMap<&'static [u8], Method>> = LazyLock::new(||
     ])
 });
 
-#[derive(Debug)]
-pub struct HttpHandler {
-    pub stream: TcpStream,
+/// Standard reason phrase for a status code, empty for codes without one
+pub fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        100 => "Continue",
+        200 => "Ok",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        410 => "Gone",
+        418 => "I'm a teapot",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+// Fails with `BadRequestLine` if the token isn't of the form `HTTP/<digit>.<digit>`
+fn parse_version(token: &[u8]) -> Result<HttpVersion, HttpError> {
+    match token {
+        b"HTTP/1.0" => Ok(HttpVersion::Http1_0),
+        b"HTTP/1.1" => Ok(HttpVersion::Http1_1),
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            Err(HttpError::UnsupportedVersion)
+        }
+        _ => Err(HttpError::BadRequestLine),
+    }
+}
+
+// How the body of a response is delimited
+#[derive(Clone, Copy)]
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+    /// The end of the body is signaled by closing the connection, for HTTP/1.0 clients that don't know chunked
+    UntilClose,
+}
+
+// Largest chunk written by `write_chunked`
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// Buffers of finished connections are kept per thread to be reused by the next ones
+const BUFFER_POOL_SIZE: usize = 64;
+// Buffers that grew past this are dropped rather than kept around forever
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    static BUFFER_POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Transport a connection is served over, such as a plain `TcpStream` or a TLS stream wrapping one
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Any {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Any> Stream for T {}
+
+/// Reads requests from and writes responses to `stream`. Any `AsyncRead + AsyncWrite` transport works, connections
+/// accepted by the server use a boxed [`Stream`] so plain and TLS clients share one type
+pub struct HttpHandler<S = Box<dyn Stream>> {
+    pub stream: S,
+    /// Address of the client, if the transport has one
+    pub peer_addr: Option<SocketAddr>,
+    /// Whether the transport is TLS, the handler itself never looks at it
+    pub secure: bool,
+    /// Whether the connection should stay open after the last response written
+    pub keep_open: bool,
+    /// How long to wait for a request before giving up on the connection
+    pub read_timeout: Option<Duration>,
+    /// Longest request line accepted, longer ones are answered with 414
+    pub max_request_line: usize,
+    /// Largest header section accepted, larger ones are answered with 431
+    pub max_headers_size: usize,
+    /// Accept request lines without a version as HTTP/0.9
+    pub allow_http09: bool,
+    /// Reject requests with ambiguous framing or bytes other hops could interpret differently
+    pub strict: bool,
+    /// Status code of the last response written, 0 if nothing was written for the current request
+    pub response_status: u16,
+    /// Body bytes sent for the current request, not counting headers or chunk framing
+    pub response_bytes: u64,
+    /// When the first byte of the current request arrived
+    pub received_at: Option<Instant>,
+    /// Body bytes of the current request not read yet
+    unread_body: u64,
+    /// Body bytes per second sent over the connection, 0 for no limit
+    pub rate_limit: u64,
+    /// Token bucket for `rate_limit`, when it was last refilled and what was left
+    bucket: (Instant, f64),
+    read_deadline: Option<Instant>,
+    /// Bytes received but not consumed yet, which may include the start of a pipelined request
+    rbuf: Vec<u8>,
     buf: Vec<u8>,
 }
 
-impl HttpHandler {
-    pub fn new(stream: TcpStream) -> Self {
+fn pooled_buffer() -> Vec<u8> {
+    BUFFER_POOL
+        .with_borrow_mut(|pool| pool.pop())
+        .unwrap_or_else(|| Vec::with_capacity(1024))
+}
+
+impl<S> fmt::Debug for HttpHandler<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpHandler")
+            .field("peer_addr", &self.peer_addr)
+            .field("keep_open", &self.keep_open)
+            .field("response_status", &self.response_status)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> HttpHandler<S> {
+    pub fn new(stream: S) -> Self {
         Self {
             stream,
-            buf: Vec::with_capacity(1024),
+            peer_addr: None,
+            secure: false,
+            keep_open: false,
+            read_timeout: None,
+            max_request_line: 8 * 1024,
+            max_headers_size: 8 * 1024,
+            allow_http09: false,
+            strict: false,
+            response_status: 0,
+            response_bytes: 0,
+            received_at: None,
+            unread_body: 0,
+            rate_limit: 0,
+            bucket: (Instant::now(), 0.0),
+            read_deadline: None,
+            rbuf: pooled_buffer(),
+            buf: pooled_buffer(),
         }
     }
 
-    pub async fn read_request_line(&mut self) -> io::Result<RequestLine> {
-        let mut cursor = 0;
-        let reqline_end = loop {
-            let n = self.stream.read_buf(&mut self.buf).await?;
-            if n == 0 {
-                return Err(Error::other("connection closed"));
-            }
-            if let Some(i) = self.buf[cursor..].array_windows::<2>().position(|v| v == b"\r\n") {
-                break cursor + i;
-            }
-            // If we didn't find the CRLF, we don't need to re-scan the entire buffer next time
-            cursor = self.buf.len() - 1;
+    pub async fn read_request(&mut self) -> Result<Request, HttpError> {
+        let line = self.read_request_line().await?;
+        // Simple requests end with the request line
+        let headers = match line.version {
+            HttpVersion::Http0_9 => Headers::default(),
+            _ => self.read_headers().await?,
+        };
+        // Bodies the response doesn't read still have to be skipped, for that their end has to be known
+        if headers.get("Transfer-Encoding").is_some() {
+            return Err(HttpError::LengthRequired);
+        }
+        self.unread_body = match headers.get("Content-Length") {
+            Some(v) => parse_content_length(v).ok_or(HttpError::BadHeader)?,
+            None => 0,
         };
+        Ok(Request { line, headers })
+    }
 
-        let mut parts = self.buf[..reqline_end].split(|&v| v == b' ');
-        let &method = parts
+    /// Body bytes of the current request that nothing read yet
+    pub fn unread_body(&self) -> u64 {
+        self.unread_body
+    }
+
+    /// Reads and throws away what's left of the request body, so the next request can be parsed
+    pub async fn discard_body(&mut self) -> io::Result<()> {
+        self.read_body(self.unread_body, &mut tokio::io::sink()).await
+    }
+
+    pub async fn read_request_line(&mut self) -> Result<RequestLine, HttpError> {
+        // Nothing was answered for this request yet
+        self.keep_open = false;
+        self.response_status = 0;
+        self.response_bytes = 0;
+        // A single deadline for the whole request, so a client can't keep us waiting by trickling bytes
+        self.read_deadline = self.read_timeout.map(|v| Instant::now() + v);
+        // Pipelined bytes may already be waiting, otherwise the clock starts once the first read returns
+        self.received_at = (!self.rbuf.is_empty()).then(Instant::now);
+        let reqline_end = self
+            .read_until(b"\r\n", self.max_request_line, HttpError::UriTooLong)
+            .await?;
+
+        let mut parts = self.rbuf[..reqline_end].split(|&v| v == b' ');
+        let method = parts
             .next()
-            .and_then(|v| METHODS_HASH.get(v))
-            .ok_or(Error::other("invalid request line"))?;
+            .filter(|v| !v.is_empty() && v.iter().all(|v| is_token_byte(*v)))
+            .ok_or(HttpError::BadRequestLine)?;
+        let &method = METHODS_HASH.get(method).ok_or(HttpError::UnknownMethod)?;
+        // Control bytes have no place in a URI and could end up in the filesystem or forge lines in the access log
         let uri = parts
             .next()
+            .filter(|v| !v.iter().any(u8::is_ascii_control))
             .and_then(|v| std::str::from_utf8(v).ok())
-            .ok_or(Error::other("invalid request line"))?
+            .ok_or(HttpError::BadRequestLine)?
             .to_string();
-        let version = parts
-            .next()
-            .map(|v| match v {
-                b"HTTP/1.0" => HttpVersion::Http1_0,
-                b"HTTP/1.1" => HttpVersion::Http1_1,
-                _ => HttpVersion::Unknown,
-            })
-            .ok_or(Error::other("invalid request line"))?;
+        let version = match parts.next() {
+            // HTTP/0.9 only ever had GET
+            None if self.allow_http09 && method == Method::Get => HttpVersion::Http0_9,
+            v => parse_version(v.ok_or(HttpError::BadRequestLine)?)?,
+        };
+        if self.strict && (parts.next().is_some() || self.rbuf[..reqline_end].iter().any(|v| is_forbidden_byte(*v))) {
+            return Err(HttpError::BadRequestLine);
+        }
+        self.rbuf.drain(..reqline_end + 2);
         Ok(RequestLine { method, uri, version })
     }
 
-    fn prepare_response_body(&mut self, status: &str, ctype: &str, clen: usize) {
+    /// Reads the header section following the request line, up to and including the empty line that ends it
+    pub async fn read_headers(&mut self) -> Result<Headers, HttpError> {
+        let mut headers = Headers::default();
+        let mut consumed = 0;
+        loop {
+            let limit = self.max_headers_size.saturating_sub(consumed);
+            let line_end = self.read_until(b"\r\n", limit, HttpError::HeadersTooLarge).await?;
+            if line_end == 0 {
+                self.rbuf.drain(..2);
+                if self.strict && !has_clean_framing(&headers) {
+                    return Err(HttpError::BadHeader);
+                }
+                return Ok(headers);
+            }
+            let line = &self.rbuf[..line_end];
+            // Obsolete line folding (RFC 9112 5.2), we are allowed to reject it instead of unfolding
+            if line[0] == b' ' || line[0] == b'\t' {
+                return Err(HttpError::BadHeader);
+            }
+            let (name, value) = std::str::from_utf8(line)
+                .ok()
+                .and_then(|v| v.split_once(':'))
+                .filter(|(name, _)| !name.is_empty() && !name.ends_with([' ', '\t']))
+                .ok_or(HttpError::BadHeader)?;
+            if self.strict && (!name.bytes().all(is_token_byte) || value.bytes().any(is_forbidden_byte)) {
+                return Err(HttpError::BadHeader);
+            }
+            headers.append(name, value.trim_matches([' ', '\t']));
+            consumed += line_end + 2;
+            self.rbuf.drain(..line_end + 2);
+        }
+    }
+
+    // Returns the position of `delim` in `rbuf`, reading more from the stream until it shows up. Fails with `overflow`
+    // if `limit` bytes were buffered without finding it
+    async fn read_until<const N: usize>(
+        &mut self,
+        delim: &[u8; N],
+        limit: usize,
+        overflow: HttpError,
+    ) -> Result<usize, HttpError> {
+        let mut cursor = 0;
+        loop {
+            if let Some(i) = self.rbuf[cursor..].array_windows::<N>().position(|v| v == delim) {
+                if cursor + i > limit {
+                    return Err(overflow);
+                }
+                return Ok(cursor + i);
+            }
+            if self.rbuf.len() > limit {
+                return Err(overflow);
+            }
+            // If we didn't find it, we don't need to re-scan the entire buffer next time
+            cursor = self.rbuf.len().saturating_sub(N - 1);
+            let read = self.stream.read_buf(&mut self.rbuf);
+            let n = match self.read_deadline {
+                Some(deadline) => timeout_at(deadline, read).await.map_err(|_| HttpError::Timeout {
+                    partial: !self.rbuf.is_empty(),
+                })??,
+                None => read.await?,
+            };
+            if n == 0 {
+                return Err(HttpError::ConnectionClosed);
+            }
+            self.received_at.get_or_insert_with(Instant::now);
+        }
+    }
+
+    // `body` is the content type and framing, `None` for responses that can't have a body
+    fn prepare_response_body(
+        &mut self,
+        status: &str,
+        body: Option<(&str, BodyLength)>,
+        encoding: Option<&str>,
+        headers: &[(&str, &str)],
+        options: &ResponseOptions<'_>,
+    ) {
+        // After an error we can't be sure the client and us agree on where the next request starts
+        let is_error = status.starts_with('4') || status.starts_with('5');
+        let until_close = matches!(body, Some((_, BodyLength::UntilClose)));
+        self.keep_open = options.keep_open && (!is_error || options.keep_alive_after_error) && !until_close;
+        self.response_status = status.split(' ').next().and_then(|v| v.parse().ok()).unwrap_or(0);
         self.buf.clear();
+        // HTTP/0.9 responses are only the body, ended by closing the connection
+        if options.version == HttpVersion::Http0_9 {
+            self.keep_open = false;
+            return;
+        }
         let date_header = httpdate::fmt_http_date(SystemTime::now());
-        write!(&mut self.buf, "HTTP/1.0 {}\r\n", status).unwrap();
-        write!(&mut self.buf, "Date: {}\r\n", date_header).unwrap();
-        write!(&mut self.buf, "Content-Type: {}\r\n", ctype).unwrap();
-        write!(&mut self.buf, "Content-Length: {}\r\n", clen).unwrap();
+        write!(&mut self.buf, "{} {}\r\n", options.version.as_str(), status).unwrap();
+        match body {
+            Some((_, BodyLength::Fixed(clen))) => write!(&mut self.buf, "Content-Length: {}\r\n", clen).unwrap(),
+            Some((_, BodyLength::Chunked)) => write!(&mut self.buf, "Transfer-Encoding: chunked\r\n").unwrap(),
+            Some((_, BodyLength::UntilClose)) | None => {}
+        }
+        let connection = if self.keep_open { "keep-alive" } else { "close" };
+        let compressed = encoding.is_some();
+        let encoding = encoding.or(options.content_encoding);
+        let standard = [
+            Some(("Date", date_header.as_str())),
+            options.server.map(|v| ("Server", v)),
+            body.map(|(ctype, _)| ("Content-Type", ctype)),
+            encoding.map(|v| ("Content-Encoding", v)),
+            encoding.map(|_| ("Vary", "Accept-Encoding")),
+            Some(("Connection", connection)),
+        ];
+        for (name, value) in standard.iter().flatten().chain(headers).chain(options.extra_headers) {
+            if options.strip_headers.iter().any(|v| v.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            // The encoded body isn't byte-for-byte the file, so the validator can only be weak
+            if compressed && name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                write!(&mut self.buf, "{}: W/{}\r\n", name, value).unwrap();
+                continue;
+            }
+            write!(&mut self.buf, "{}: {}\r\n", name, value).unwrap();
+        }
         write!(&mut self.buf, "\r\n").unwrap();
     }
 
-    pub async fn write_status(&mut self, status: &str, options: &ResponseOptions) -> io::Result<()> {
-        self.prepare_response_body(status, "text", status.len());
+    /// Copies the next `len` bytes of request body into `writer`, starting with whatever was read along with the headers.
+    /// Fails with `UnexpectedEof` if the client hangs up before sending all of it
+    pub async fn read_body<W: AsyncWrite + Unpin + ?Sized>(&mut self, len: u64, writer: &mut W) -> io::Result<()> {
+        self.unread_body = self.unread_body.saturating_sub(len);
+        let buffered = self.rbuf.len().min(usize::try_from(len).unwrap_or(usize::MAX));
+        writer.write_all(&self.rbuf[..buffered]).await?;
+        self.rbuf.drain(..buffered);
+        let mut remaining = len - buffered as u64;
+        // Bodies can be arbitrarily large, so each read gets the full timeout instead of sharing a deadline
+        while remaining > 0 {
+            let mut limited = (&mut self.stream).take(remaining.min(CHUNK_SIZE as u64));
+            let read = limited.read_buf(&mut self.rbuf);
+            let n = match self.read_timeout {
+                Some(v) => tokio::time::timeout(v, read)
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+                None => read.await?,
+            };
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            writer.write_all(&self.rbuf).await?;
+            self.rbuf.clear();
+            remaining -= n as u64;
+        }
+        Ok(())
+    }
+
+    /// Sends an interim `100 Continue` response, telling a client waiting on `Expect: 100-continue` to send the body
+    pub async fn write_continue(&mut self) -> io::Result<()> {
+        self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await
+    }
+
+    /// Sends an interim `103 Early Hints` response, the final response must still be written afterwards
+    pub async fn write_early_hints(&mut self, links: &[String]) -> io::Result<()> {
+        self.buf.clear();
+        write!(&mut self.buf, "HTTP/1.1 103 Early Hints\r\n")?;
+        for link in links {
+            write!(&mut self.buf, "Link: {}\r\n", link)?;
+        }
+        write!(&mut self.buf, "\r\n")?;
+        self.stream.write_all(&self.buf).await
+    }
+
+    /// Sends a response without a body or any of the headers describing one, as `304 Not Modified` requires
+    pub async fn write_empty(
+        &mut self,
+        status: &str,
+        headers: &[(&str, &str)],
+        options: &ResponseOptions<'_>,
+    ) -> io::Result<()> {
+        self.prepare_response_body(status, None, None, headers, options);
+        self.stream.write_all(&self.buf).await
+    }
+
+    pub async fn write_status(
+        &mut self,
+        status: &str,
+        headers: &[(&str, &str)],
+        options: &ResponseOptions<'_>,
+    ) -> io::Result<()> {
+        self.prepare_response_body(
+            status,
+            Some(("text/plain; charset=utf-8", BodyLength::Fixed(status.len()))),
+            None,
+            headers,
+            options,
+        );
         if !options.omit_body {
             write!(&mut self.buf, "{}", status)?;
-            self.stream.write_all(&mut self.buf).await?;
+            self.response_bytes += status.len() as u64;
         }
+        self.stream.write_all(&self.buf).await?;
         Ok(())
     }
 
     pub async fn write_buffer(
         &mut self,
         status: &str,
-        mut buf: Vec<u8>,
+        buf: Vec<u8>,
         ctype: &str,
-        options: &ResponseOptions,
+        headers: &[(&str, &str)],
+        options: &ResponseOptions<'_>,
     ) -> io::Result<()> {
-        self.prepare_response_body(status, ctype, buf.len());
+        let mut buf = buf;
+        let mut encoding = None;
+        if options.gzip && is_compressible(ctype) {
+            let mut compressed = Vec::new();
+            GzipEncoder::new(buf.as_slice()).read_to_end(&mut compressed).await?;
+            buf = compressed;
+            encoding = Some("gzip");
+        }
+        let body = Some((ctype, BodyLength::Fixed(buf.len())));
+        self.prepare_response_body(status, body, encoding, headers, options);
+        self.stream.write_all(&self.buf).await?;
         if !options.omit_body {
-            self.stream.write_all(&mut self.buf).await?;
-            self.stream.write_all(&mut buf).await?;
+            self.response_bytes += self.copy_body(&mut buf.as_slice()).await?;
         }
         Ok(())
     }
@@ -143,16 +634,394 @@ impl HttpHandler {
         mut cbody: B,
         ctype: &str,
         clen: usize,
-        options: &ResponseOptions,
+        headers: &[(&str, &str)],
+        options: &ResponseOptions<'_>,
     ) -> io::Result<()>
     where
         B: AsyncRead + Unpin,
     {
-        self.prepare_response_body(status, ctype, clen);
+        // The compressed length isn't known up front
+        if options.gzip && is_compressible(ctype) {
+            let body = GzipEncoder::new(BufReader::new(cbody));
+            return self
+                .write_chunks(status, body, ctype, Some("gzip"), headers, options)
+                .await;
+        }
+        let body = Some((ctype, BodyLength::Fixed(clen)));
+        self.prepare_response_body(status, body, None, headers, options);
+        self.stream.write_all(&self.buf).await?;
         if !options.omit_body {
-            self.stream.write_all(&mut self.buf).await?;
-            tokio::io::copy(&mut cbody, &mut self.stream).await?;
+            self.response_bytes += self.copy_body(&mut cbody).await?;
         }
         Ok(())
     }
+
+    /// Streams a body of unknown length with chunked transfer encoding. HTTP/1.0 clients don't support it, so for them
+    /// the body is sent as is and the connection closed after it
+    pub async fn write_chunked<B>(
+        &mut self,
+        status: &str,
+        cbody: B,
+        ctype: &str,
+        headers: &[(&str, &str)],
+        options: &ResponseOptions<'_>,
+    ) -> io::Result<()>
+    where
+        B: AsyncRead + Unpin,
+    {
+        if options.gzip && is_compressible(ctype) {
+            let body = GzipEncoder::new(BufReader::new(cbody));
+            return self
+                .write_chunks(status, body, ctype, Some("gzip"), headers, options)
+                .await;
+        }
+        self.write_chunks(status, cbody, ctype, None, headers, options).await
+    }
+
+    async fn write_chunks<B>(
+        &mut self,
+        status: &str,
+        mut cbody: B,
+        ctype: &str,
+        encoding: Option<&str>,
+        headers: &[(&str, &str)],
+        options: &ResponseOptions<'_>,
+    ) -> io::Result<()>
+    where
+        B: AsyncRead + Unpin,
+    {
+        // Neither 1.0 nor 0.9 have chunked encoding
+        if options.version != HttpVersion::Http1_1 {
+            self.prepare_response_body(
+                status,
+                Some((ctype, BodyLength::UntilClose)),
+                encoding,
+                headers,
+                options,
+            );
+            self.stream.write_all(&self.buf).await?;
+            if !options.omit_body {
+                self.response_bytes += self.copy_body(&mut cbody).await?;
+            }
+            return Ok(());
+        }
+        self.prepare_response_body(status, Some((ctype, BodyLength::Chunked)), encoding, headers, options);
+        self.stream.write_all(&self.buf).await?;
+        if options.omit_body {
+            return Ok(());
+        }
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let n = cbody.read(&mut chunk).await?;
+            self.buf.clear();
+            // A zero sized chunk is the terminator, with an empty trailer section
+            write!(&mut self.buf, "{:x}\r\n", n)?;
+            self.buf.extend_from_slice(&chunk[..n]);
+            write!(&mut self.buf, "\r\n")?;
+            self.stream.write_all(&self.buf).await?;
+            self.response_bytes += n as u64;
+            if n == 0 {
+                return Ok(());
+            }
+            self.throttle(n).await;
+        }
+    }
+
+    async fn copy_body<B>(&mut self, body: &mut B) -> io::Result<u64>
+    where
+        B: AsyncRead + Unpin,
+    {
+        if self.rate_limit == 0 {
+            return tokio::io::copy(body, &mut self.stream).await;
+        }
+        // Chunks no larger than a second's worth keep the pauses between them short
+        let mut chunk = vec![0; CHUNK_SIZE.min(self.rate_limit as usize)];
+        let mut sent = 0;
+        loop {
+            let n = body.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(sent);
+            }
+            self.stream.write_all(&chunk[..n]).await?;
+            sent += n as u64;
+            self.throttle(n).await;
+        }
+    }
+
+    // Refills the bucket for the time elapsed, up to a second's worth, and sleeps off whatever `sent` overdrew it by.
+    // The average rate holds across responses since the bucket lives as long as the connection
+    async fn throttle(&mut self, sent: usize) {
+        if self.rate_limit == 0 {
+            return;
+        }
+        let rate = self.rate_limit as f64;
+        let (refilled, tokens) = self.bucket;
+        let now = Instant::now();
+        let tokens = (tokens + now.duration_since(refilled).as_secs_f64() * rate).min(rate) - sent as f64;
+        self.bucket = (now, tokens);
+        if tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-tokens / rate)).await;
+        }
+    }
+}
+
+// `tchar` from RFC 9110 5.6.2, the only bytes allowed in a header name
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// Only CRLF may end a line, a lone CR or LF could be read as a line end by another hop
+fn is_forbidden_byte(b: u8) -> bool {
+    matches!(b, b'\r' | b'\n' | 0)
+}
+
+// Requests that different parsers could disagree on the body length of, the basis of request smuggling: repeated
+// Content-Length values, or Content-Length together with Transfer-Encoding
+fn has_clean_framing(headers: &Headers) -> bool {
+    match (headers.get("Content-Length"), headers.get("Transfer-Encoding")) {
+        (Some(_), Some(_)) => false,
+        (Some(clen), None) => !clen.contains(','),
+        _ => true,
+    }
+}
+
+// Repeated headers are combined, which is only fine when every value is the same
+fn parse_content_length(value: &str) -> Option<u64> {
+    let mut values = value.split(',').map(|v| v.trim().parse::<u64>().ok());
+    let first = values.next()??;
+    values.all(|v| v == Some(first)).then_some(first)
+}
+
+// Text-like types, other formats are usually compressed already
+pub fn is_compressible(ctype: &str) -> bool {
+    // Parameters such as the charset don't matter here
+    let ctype = ctype.split(';').next().unwrap_or("").trim_end();
+    ctype.starts_with("text/")
+        || matches!(
+            ctype,
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+impl HttpHandler<Box<dyn Stream>> {
+    /// Sends `len` bytes of `file` starting at `offset`. With the `sendfile` feature, plain TCP connections on Linux
+    /// leave the copy to the kernel whenever the body goes out unchanged, anything else goes through `write_reader`
+    #[allow(clippy::too_many_arguments)]
+    pub async fn write_file(
+        &mut self,
+        status: &str,
+        mut file: File,
+        offset: u64,
+        len: usize,
+        ctype: &str,
+        headers: &[(&str, &str)],
+        options: &ResponseOptions<'_>,
+    ) -> io::Result<()> {
+        #[cfg(all(feature = "sendfile", target_os = "linux"))]
+        {
+            let compressed = options.gzip && is_compressible(ctype);
+            // The kernel copy can't be paced, throttled connections take the regular path
+            if !options.omit_body && !compressed && self.rate_limit == 0 && self.tcp_stream().is_some() {
+                self.prepare_response_body(status, Some((ctype, BodyLength::Fixed(len))), None, headers, options);
+                self.stream.write_all(&self.buf).await?;
+                let tcp = self.tcp_stream().unwrap();
+                sendfile_all(tcp, &file, offset, len).await?;
+                self.response_bytes += len as u64;
+                return Ok(());
+            }
+        }
+        file.seek(SeekFrom::Start(offset)).await?;
+        self.write_reader(status, file.take(len as u64), ctype, len, headers, options)
+            .await
+    }
+
+    #[cfg(all(feature = "sendfile", target_os = "linux"))]
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        (&*self.stream as &dyn Any).downcast_ref::<TcpStream>()
+    }
+}
+
+// The socket is non-blocking, so this waits for it to be writable and loops over partial sends. Reading the file
+// itself can still block the worker, like any other file read does through the kernel page cache
+#[cfg(all(feature = "sendfile", target_os = "linux"))]
+async fn sendfile_all(tcp: &TcpStream, file: &File, mut offset: u64, len: usize) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut remaining = len;
+    while remaining > 0 {
+        tcp.writable().await?;
+        let sent = tcp.try_io(tokio::io::Interest::WRITABLE, || {
+            let mut off = offset as libc::off_t;
+            // SAFETY: both descriptors stay open for the duration of the call, and `off` is a valid pointer
+            let n = unsafe { libc::sendfile(tcp.as_raw_fd(), file.as_raw_fd(), &mut off, remaining) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(n as usize)
+        });
+        match sent {
+            // The file got shorter than the length already promised
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                offset += n as u64;
+                remaining -= n;
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+impl<S> Drop for HttpHandler<S> {
+    fn drop(&mut self) {
+        for mut buf in [std::mem::take(&mut self.rbuf), std::mem::take(&mut self.buf)] {
+            if buf.capacity() > MAX_POOLED_CAPACITY {
+                continue;
+            }
+            buf.clear();
+            BUFFER_POOL.with_borrow_mut(|pool| {
+                if pool.len() < BUFFER_POOL_SIZE {
+                    pool.push(buf);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A handler over a stream that yields `input` and then reports the client closed its side
+    async fn handler_for(input: &[u8]) -> HttpHandler<tokio::io::DuplexStream> {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client.write_all(input).await.unwrap();
+        HttpHandler::new(server)
+    }
+
+    async fn parse(input: &[u8], strict: bool) -> Result<Request, HttpError> {
+        let mut handler = handler_for(input).await;
+        handler.strict = strict;
+        handler.read_request().await
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_read_in_order() {
+        let mut handler = handler_for(b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nHEAD /b HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let first = handler.read_request().await.unwrap();
+        assert_eq!(first.line.uri, "/a");
+        assert_eq!(first.headers.get("host"), Some("x"));
+        let second = handler.read_request().await.unwrap();
+        assert_eq!(second.line.method, Method::Head);
+        assert_eq!(second.line.uri, "/b");
+        assert!(matches!(handler.read_request().await, Err(HttpError::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn discarded_body_is_not_parsed_as_a_request() {
+        let body = "GET /smuggled HTTP/1.1\r\n\r\n";
+        let input = format!(
+            "POST /a HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET /b HTTP/1.1\r\n\r\n",
+            body.len(),
+            body
+        );
+        let mut handler = handler_for(input.as_bytes()).await;
+        handler.read_request().await.unwrap();
+        assert_eq!(handler.unread_body(), body.len() as u64);
+        handler.discard_body().await.unwrap();
+        assert_eq!(handler.unread_body(), 0);
+        assert_eq!(handler.read_request().await.unwrap().line.uri, "/b");
+    }
+
+    #[tokio::test]
+    async fn body_framing_errors() {
+        let chunked = parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n", false).await;
+        assert!(matches!(chunked, Err(HttpError::LengthRequired)));
+        let conflicting = parse(
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+            false,
+        )
+        .await;
+        assert!(matches!(conflicting, Err(HttpError::BadHeader)));
+        let repeated = parse(
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc",
+            false,
+        )
+        .await;
+        assert_eq!(repeated.unwrap().headers.get("content-length"), Some("3, 3"));
+    }
+
+    #[tokio::test]
+    async fn parse_errors_map_to_their_status() {
+        let long_uri = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9 * 1024));
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(9 * 1024));
+        let cases: [(&[u8], Option<&str>); 10] = [
+            (b"", None),
+            (b"GET / HTTP/1.1\r", None),
+            (b"GET\r\n\r\n", Some("400 Bad Request")),
+            (b"GET / HTTP/1.1\r\nno colon\r\n\r\n", Some("400 Bad Request")),
+            (b"BREW / HTTP/1.1\r\n\r\n", Some("400 Bad Request")),
+            (long_uri.as_bytes(), Some("414 URI Too Long")),
+            (long_header.as_bytes(), Some("431 Request Header Fields Too Large")),
+            (b"GET / HTTP/2.0\r\n\r\n", Some("505 HTTP Version Not Supported")),
+            (b"GET / HTTP/0.9\r\n\r\n", Some("505 HTTP Version Not Supported")),
+            (b"GET / HTTP/banana\r\n\r\n", Some("400 Bad Request")),
+        ];
+        for (input, status) in cases {
+            let err = parse(input, false).await.unwrap_err();
+            assert_eq!(err.status(), status, "{:?}", String::from_utf8_lossy(input));
+        }
+        assert_eq!(
+            HttpError::Timeout { partial: true }.status(),
+            Some("408 Request Timeout")
+        );
+        assert_eq!(HttpError::Timeout { partial: false }.status(), None);
+        assert_eq!(HttpError::LengthRequired.status(), Some("411 Length Required"));
+    }
+
+    #[tokio::test]
+    async fn strict_mode_rejects_malformed_framing() {
+        let cases: [&[u8]; 6] = [
+            b"GET / HTTP/1.1\r\nX: a\r\n b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nX : a\r\n\r\n",
+            b"GET / HTTP/1.1\r\nX(y): a\r\n\r\n",
+            b"GET / HTTP/1.1\r\nX: a\rb\r\n\r\n",
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 1\r\n\r\na",
+        ];
+        for input in cases {
+            let err = parse(input, true).await.unwrap_err();
+            assert_eq!(
+                err.status(),
+                Some("400 Bad Request"),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+        assert!(parse(b"GET / HTTP/1.1\r\nX(y): a\r\n\r\n", false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn simple_requests_need_allow_http09() {
+        assert!(matches!(
+            parse(b"GET /\r\n", false).await,
+            Err(HttpError::BadRequestLine)
+        ));
+        let mut handler = handler_for(b"GET /\r\n").await;
+        handler.allow_http09 = true;
+        assert_eq!(handler.read_request().await.unwrap().line.version, HttpVersion::Http0_9);
+    }
+
+    #[tokio::test]
+    async fn buffers_are_reused_by_the_next_connection() {
+        let handler = handler_for(b"").await;
+        let mut used = [handler.rbuf.as_ptr(), handler.buf.as_ptr()];
+        drop(handler);
+        let handler = handler_for(b"").await;
+        let mut reused = [handler.rbuf.as_ptr(), handler.buf.as_ptr()];
+        used.sort();
+        reused.sort();
+        assert_eq!(used, reused);
+    }
 }
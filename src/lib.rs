@@ -1,141 +1,3429 @@
-#![feature(array_windows)]
-#![feature(io_error_other)]
-#![feature(lazy_cell)]
-#![feature(try_blocks)]
+mod cache;
 mod http;
+#[cfg(unix)]
+mod privileges;
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fmt;
+use std::future::Future;
 use std::io::Write as _;
-use std::io::{self, Error};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::io::{self, Error, SeekFrom};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use mime_guess::Mime;
+use regex::{Regex, RegexSet};
 use tokio::fs::{read_dir, File, OpenOptions};
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket};
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
 use tokio_util::sync::CancellationToken;
 
-use http::{HttpHandler, HttpVersion, Method, ResponseOptions};
+use cache::{CachedFile, FileCache};
+use http::{is_compressible, reason_phrase, HttpError};
+pub use http::{Headers, HttpHandler, HttpVersion, Method, Request, RequestLine, ResponseOptions, Stream};
+
+#[derive(Debug)]
+pub struct Config {
+    pub root: PathBuf,
+    /// If not empty, only files with one of these extensions are served or listed
+    pub allow_ext: Vec<String>,
+    /// Only generate directory listings for the root directory
+    pub list_root_only: bool,
+    /// Minify served JSON files unless `?pretty=1` is requested
+    pub minify_json: bool,
+    /// Don't close keep-alive connections after responding with a 4xx/5xx status
+    pub keep_alive_after_error: bool,
+    pub trailing_slash_policy: TrailingSlashPolicy,
+    /// User to switch to after binding (Unix only)
+    pub user: Option<String>,
+    /// Group to switch to after binding, defaults to the user's primary group (Unix only)
+    pub group: Option<String>,
+    /// Chroot into `root` after binding (Unix only)
+    pub chroot: bool,
+    /// Detect the type of files with unknown extensions from their first bytes
+    pub magic_sniff: bool,
+    /// Response headers to never send, matched case-insensitively
+    pub strip_headers: Vec<String>,
+    /// Wait this long before answering each request. Only meant for testing how clients handle slow servers
+    pub response_delay: Option<Duration>,
+    /// Close every connection after a single response, regardless of the HTTP version
+    pub disable_keepalive: bool,
+    /// Retry missing paths with `.html` appended, so `/about` serves `about.html`
+    pub try_html_extension: bool,
+    /// Upper bound on the time from the first byte of a request to finishing its response
+    pub request_deadline: Option<Duration>,
+    /// Show links to every parent directory at the top of listings
+    pub listing_breadcrumbs: bool,
+    /// Custom handlers tried, in order, before serving files. See [`Config::route`]
+    pub routes: Vec<Route>,
+    /// `Link` header values sent in a `103 Early Hints` response before HTML files
+    pub early_hint_links: Vec<String>,
+    /// Files served in place of the listing when a directory contains one, tried in order
+    pub index_files: Vec<String>,
+    /// How long to wait for a client to send its request line
+    pub read_timeout: Option<Duration>,
+    /// Largest body, in bytes, that may be assembled in memory before sending it
+    pub max_memory_response: Option<usize>,
+    /// Alternate files served for `/` to clients whose User-Agent matches, the first matching rule wins
+    pub ua_rules: Vec<UaRule>,
+    /// Log requests that take at least this long to be answered
+    pub log_slow: Option<Duration>,
+    /// Never gzip responses, even for clients that accept it
+    pub disable_compression: bool,
+    /// Bodies shorter than this many bytes are never compressed, it isn't worth it and can even make them larger
+    pub compress_min_size: usize,
+    /// Files with one of these extensions are never compressed, such as already minified ones
+    pub no_compress_ext: Vec<String>,
+    /// Only compress over TLS, plain text connections are left open to BREACH style attacks otherwise
+    pub compress_tls_only: bool,
+    /// Served at `/robots.txt` when the directory doesn't have one
+    pub robots: Option<String>,
+    /// Generate `/sitemap.xml` from the HTML files when the directory doesn't have one
+    pub generate_sitemap: bool,
+    /// Reject request paths with more segments than this with 400
+    pub max_path_depth: Option<usize>,
+    /// Longest request line accepted, in bytes
+    pub max_request_line: usize,
+    /// Largest header section accepted, in bytes
+    pub max_headers_size: usize,
+    /// Answer request lines without a version as HTTP/0.9, with only the body
+    pub allow_http09: bool,
+    pub log_format: LogFormat,
+    /// Seconds clients may cache files for without revalidating, 0 sends `no-cache`
+    pub cache_max_age: u64,
+    /// Extension to content type mappings checked before the built-in ones, keys are lowercase
+    pub mime_types: HashMap<String, Mime>,
+    /// Origins allowed to make cross-origin requests, `*` allows any. Empty disables CORS
+    pub cors: Vec<String>,
+    /// Response headers scripts on allowed origins may read, besides the always readable simple ones
+    pub cors_expose_headers: Vec<String>,
+    /// Reject requests with ambiguous framing or invalid characters instead of tolerating them
+    pub strict: bool,
+    /// `user:password` required through HTTP Basic authentication for every request
+    pub auth: Option<String>,
+    /// PEM certificate chain, serving over TLS when set along with `tls_key`
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// Answer `/` with this instead of looking at the filesystem
+    pub root_response: Option<RootResponse>,
+    /// Callbacks for connection and request events
+    pub hooks: Option<Box<dyn Hooks>>,
+    /// Accept `PUT` and `DELETE` requests, writing or removing files under root
+    pub enable_upload: bool,
+    /// Hide names starting with a dot from listings, and answer 404 for them and anything under them
+    pub no_dotfiles: bool,
+    /// Connections served at once, further ones aren't accepted until one closes. 0 means no limit
+    pub max_connections: usize,
+    /// Answer browser navigations to missing paths with the root index file, for client side routing
+    pub spa: bool,
+    /// How much to print besides errors
+    pub verbosity: Verbosity,
+    /// Addresses `serve` listens on, anything `tokio::net::lookup_host` resolves
+    pub addresses: Vec<String>,
+    /// How long `serve` waits for open connections once cancelled, it fails if some are still open after it
+    pub shutdown_timeout: Option<Duration>,
+    /// Serve a single request, then shut down like `serve` had been cancelled
+    pub once: bool,
+    /// Prefix the server is mounted under, such as `/files` behind a reverse proxy. Requests outside it get 404 and
+    /// generated links include it
+    pub base_path: String,
+    /// Sent as the `Server` header, `httpfs/<version>` by default
+    pub server_header: Option<String>,
+    /// Generates responses for paths that aren't files, see `Config::virtual_paths`
+    pub virtual_paths: Option<VirtualPaths>,
+    /// Body bytes per second sent over each connection, 0 means no limit
+    pub rate_limit: u64,
+    /// Bytes of small files kept in memory between requests, 0 disables the cache
+    pub cache_size: usize,
+    /// Largest file put in the cache
+    pub cache_file_size: usize,
+    /// Glob patterns for paths under root, hidden the same way dotfiles are with `no_dotfiles`. A `.httpfsignore`
+    /// file in root adds one pattern per line
+    pub exclude: Vec<String>,
+    /// Largest body accepted by `PUT`, larger uploads are refused with 413
+    pub max_upload_size: Option<u64>,
+    /// Largest request body read just to be thrown away, requests with larger ones that aren't uploads get 413 and
+    /// the connection is closed
+    pub discard_limit: u64,
+}
+
+impl Config {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            allow_ext: Vec::new(),
+            list_root_only: false,
+            minify_json: false,
+            keep_alive_after_error: false,
+            trailing_slash_policy: TrailingSlashPolicy::default(),
+            user: None,
+            group: None,
+            chroot: false,
+            magic_sniff: false,
+            strip_headers: Vec::new(),
+            response_delay: None,
+            disable_keepalive: false,
+            try_html_extension: false,
+            request_deadline: None,
+            listing_breadcrumbs: false,
+            routes: Vec::new(),
+            early_hint_links: Vec::new(),
+            index_files: vec!["index.html".to_string()],
+            read_timeout: None,
+            max_memory_response: None,
+            ua_rules: Vec::new(),
+            log_slow: None,
+            disable_compression: false,
+            compress_min_size: 1024,
+            no_compress_ext: Vec::new(),
+            compress_tls_only: false,
+            robots: None,
+            generate_sitemap: false,
+            max_path_depth: None,
+            max_request_line: 8 * 1024,
+            max_headers_size: 8 * 1024,
+            allow_http09: false,
+            log_format: LogFormat::default(),
+            cache_max_age: 0,
+            mime_types: HashMap::new(),
+            cors: Vec::new(),
+            cors_expose_headers: Vec::new(),
+            strict: false,
+            auth: None,
+            tls_cert: None,
+            tls_key: None,
+            root_response: None,
+            hooks: None,
+            enable_upload: false,
+            no_dotfiles: false,
+            max_connections: 1024,
+            spa: false,
+            verbosity: Verbosity::Normal,
+            addresses: vec!["127.0.0.1:8000".to_string()],
+            shutdown_timeout: None,
+            once: false,
+            base_path: String::new(),
+            server_header: Some(concat!("httpfs/", env!("CARGO_PKG_VERSION")).to_string()),
+            virtual_paths: None,
+            rate_limit: 0,
+            cache_size: 0,
+            cache_file_size: 64 * 1024,
+            exclude: Vec::new(),
+            max_upload_size: None,
+            discard_limit: 64 * 1024,
+        }
+    }
+
+    /// Every field is public, these setters are only a shorthand for building a config in one expression when
+    /// embedding the server
+    ///
+    /// ```no_run
+    /// # async fn example() -> std::io::Result<()> {
+    /// let config = http_rust::Config::new("/srv/www".into())
+    ///     .addresses(["0.0.0.0:8080", "[::]:8080"])
+    ///     .max_connections(256)
+    ///     .cache_max_age(3600);
+    /// http_rust::serve(config, Default::default()).await
+    /// # }
+    /// ```
+    pub fn addresses(mut self, addresses: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.addresses = addresses.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn request_deadline(mut self, deadline: Duration) -> Self {
+        self.request_deadline = Some(deadline);
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// `user:password` for HTTP Basic authentication
+    pub fn auth(mut self, credentials: impl Into<String>) -> Self {
+        self.auth = Some(credentials.into());
+        self
+    }
+
+    pub fn cors(mut self, origins: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.cors = origins.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn cache_max_age(mut self, secs: u64) -> Self {
+        self.cache_max_age = secs;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    pub fn disable_compression(mut self, disable: bool) -> Self {
+        self.disable_compression = disable;
+        self
+    }
+
+    /// Adds the mappings of an Apache style `mime.types` file, each line being a type followed by its extensions
+    pub fn load_mime_types(&mut self, path: &Path) -> io::Result<()> {
+        let content = std::fs::read_to_string(path)?;
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let Some(mime) = fields.next() else {
+                continue;
+            };
+            let mime: Mime = mime
+                .parse()
+                .map_err(|_| Error::new(io::ErrorKind::InvalidData, format!("invalid type '{}'", mime)))?;
+            for ext in fields {
+                self.mime_types.insert(ext.to_ascii_lowercase(), mime.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers a handler for requests with `method` whose path starts with `prefix`.
+    ///
+    /// The handler runs before the method is checked and before any file lookup, so it can answer methods the server
+    /// otherwise rejects. It must either write a full response and resolve to `true`, or write nothing and resolve to
+    /// `false` to let the request through to the next route and finally the static file handling.
+    ///
+    /// ```no_run
+    /// # let mut config = http_rust::Config::new(".".into());
+    /// config.route(http_rust::Method::Post, "/api", |_request, handler, options| {
+    ///     Box::pin(async move {
+    ///         handler.write_buffer("200 Ok", b"{}".to_vec(), "application/json", &[], options).await?;
+    ///         Ok(true)
+    ///     })
+    /// });
+    /// ```
+    pub fn route<F>(&mut self, method: Method, prefix: impl Into<String>, handler: F)
+    where
+        F: for<'a> Fn(&'a Request, &'a mut HttpHandler, &'a ResponseOptions<'a>) -> RouteFuture<'a>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            prefix: prefix.into(),
+            handler: Box::new(handler),
+        });
+    }
+
+    /// Sets a handler consulted for every request after the routes and before anything touches the filesystem. The
+    /// request's URI is as the client sent it, query and base path included.
+    ///
+    /// Returning the status, body and content type answers the request with them, `None` falls through to the normal
+    /// static file handling. Unlike routes it's synchronous, meant for small generated responses.
+    ///
+    /// ```no_run
+    /// # let mut config = http_rust::Config::new(".".into());
+    /// config.virtual_paths(|line| match line.uri.as_str() {
+    ///     "/health" => Some(("200 Ok".into(), b"ok".to_vec(), "text/plain".into())),
+    ///     _ => None,
+    /// });
+    /// ```
+    pub fn virtual_paths<F>(&mut self, handler: F)
+    where
+        F: Fn(&RequestLine) -> Option<VirtualResponse> + Send + Sync + 'static,
+    {
+        self.virtual_paths = Some(VirtualPaths(Box::new(handler)));
+    }
+}
+
+/// One `key: value` line per option, for `--print-config`. Unlike `Debug` it never shows the auth password
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "root: {}", self.root.display())?;
+        writeln!(f, "allow_ext: {:?}", self.allow_ext)?;
+        writeln!(f, "list_root_only: {:?}", self.list_root_only)?;
+        writeln!(f, "minify_json: {:?}", self.minify_json)?;
+        writeln!(f, "keep_alive_after_error: {:?}", self.keep_alive_after_error)?;
+        writeln!(f, "trailing_slash_policy: {:?}", self.trailing_slash_policy)?;
+        writeln!(f, "user: {:?}", self.user)?;
+        writeln!(f, "group: {:?}", self.group)?;
+        writeln!(f, "chroot: {:?}", self.chroot)?;
+        writeln!(f, "magic_sniff: {:?}", self.magic_sniff)?;
+        writeln!(f, "strip_headers: {:?}", self.strip_headers)?;
+        writeln!(f, "response_delay: {:?}", self.response_delay)?;
+        writeln!(f, "disable_keepalive: {:?}", self.disable_keepalive)?;
+        writeln!(f, "try_html_extension: {:?}", self.try_html_extension)?;
+        writeln!(f, "request_deadline: {:?}", self.request_deadline)?;
+        writeln!(f, "listing_breadcrumbs: {:?}", self.listing_breadcrumbs)?;
+        writeln!(f, "routes: {:?}", self.routes)?;
+        writeln!(f, "early_hint_links: {:?}", self.early_hint_links)?;
+        writeln!(f, "index_files: {:?}", self.index_files)?;
+        writeln!(f, "read_timeout: {:?}", self.read_timeout)?;
+        writeln!(f, "max_memory_response: {:?}", self.max_memory_response)?;
+        writeln!(f, "ua_rules: {:?}", self.ua_rules)?;
+        writeln!(f, "log_slow: {:?}", self.log_slow)?;
+        writeln!(f, "disable_compression: {:?}", self.disable_compression)?;
+        writeln!(f, "compress_min_size: {:?}", self.compress_min_size)?;
+        writeln!(f, "no_compress_ext: {:?}", self.no_compress_ext)?;
+        writeln!(f, "compress_tls_only: {:?}", self.compress_tls_only)?;
+        writeln!(f, "robots: {:?}", self.robots)?;
+        writeln!(f, "generate_sitemap: {:?}", self.generate_sitemap)?;
+        writeln!(f, "max_path_depth: {:?}", self.max_path_depth)?;
+        writeln!(f, "max_request_line: {:?}", self.max_request_line)?;
+        writeln!(f, "max_headers_size: {:?}", self.max_headers_size)?;
+        writeln!(f, "allow_http09: {:?}", self.allow_http09)?;
+        writeln!(f, "log_format: {:?}", self.log_format)?;
+        writeln!(f, "cache_max_age: {:?}", self.cache_max_age)?;
+        writeln!(
+            f,
+            "mime_types: {:?}",
+            self.mime_types.iter().collect::<BTreeMap<_, _>>()
+        )?;
+        writeln!(f, "cors: {:?}", self.cors)?;
+        writeln!(f, "cors_expose_headers: {:?}", self.cors_expose_headers)?;
+        writeln!(f, "strict: {:?}", self.strict)?;
+        // Only the user name, the password must not end up in logs or terminal scrollback
+        let auth = self
+            .auth
+            .as_ref()
+            .map(|v| format!("{}:***", v.split_once(':').map_or(v.as_str(), |(user, _)| user)));
+        writeln!(f, "auth: {:?}", auth)?;
+        writeln!(f, "tls_cert: {:?}", self.tls_cert)?;
+        writeln!(f, "tls_key: {:?}", self.tls_key)?;
+        writeln!(f, "root_response: {:?}", self.root_response)?;
+        writeln!(f, "hooks: {:?}", self.hooks)?;
+        writeln!(f, "enable_upload: {:?}", self.enable_upload)?;
+        writeln!(f, "no_dotfiles: {:?}", self.no_dotfiles)?;
+        writeln!(f, "max_connections: {:?}", self.max_connections)?;
+        writeln!(f, "spa: {:?}", self.spa)?;
+        writeln!(f, "verbosity: {:?}", self.verbosity)?;
+        writeln!(f, "addresses: {:?}", self.addresses)?;
+        writeln!(f, "shutdown_timeout: {:?}", self.shutdown_timeout)?;
+        writeln!(f, "once: {:?}", self.once)?;
+        writeln!(f, "base_path: {:?}", self.base_path)?;
+        writeln!(f, "server_header: {:?}", self.server_header)?;
+        writeln!(f, "virtual_paths: {:?}", self.virtual_paths)?;
+        writeln!(f, "rate_limit: {:?}", self.rate_limit)?;
+        writeln!(f, "cache_size: {:?}", self.cache_size)?;
+        writeln!(f, "cache_file_size: {:?}", self.cache_file_size)?;
+        writeln!(f, "exclude: {:?}", self.exclude)?;
+        writeln!(f, "max_upload_size: {:?}", self.max_upload_size)?;
+        writeln!(f, "discard_limit: {:?}", self.discard_limit)?;
+        Ok(())
+    }
+}
+
+pub type RouteFuture<'a> = Pin<Box<dyn Future<Output = io::Result<bool>> + Send + 'a>>;
+
+type RouteHandler =
+    Box<dyn for<'a> Fn(&'a Request, &'a mut HttpHandler, &'a ResponseOptions<'a>) -> RouteFuture<'a> + Send + Sync>;
+
+/// Status, body and content type
+pub type VirtualResponse = (String, Vec<u8>, String);
+
+type VirtualHandler = Box<dyn Fn(&RequestLine) -> Option<VirtualResponse> + Send + Sync>;
+
+pub struct VirtualPaths(VirtualHandler);
+
+impl fmt::Debug for VirtualPaths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VirtualPaths")
+    }
+}
+
+pub struct Route {
+    method: Method,
+    prefix: String,
+    handler: RouteHandler,
+}
+
+impl fmt::Debug for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Route")
+            .field("method", &self.method)
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Callbacks invoked as connections are served, for embedders that want their own metrics, logging or tracing. Every
+/// method does nothing by default, so only the interesting ones need implementing
+pub trait Hooks: Send + Sync {
+    /// A client connected, after the TLS handshake if there is one
+    fn connection_opened(&self, _peer_addr: Option<SocketAddr>) {}
+    /// A request was parsed, before anything is written for it
+    fn request_received(&self, _peer_addr: Option<SocketAddr>, _request: &Request) {}
+    /// A response was written. `request` is `None` when the request couldn't be parsed, and `elapsed` counts from its
+    /// first byte
+    fn response_sent(
+        &self,
+        _peer_addr: Option<SocketAddr>,
+        _request: Option<&Request>,
+        _status: u16,
+        _bytes: u64,
+        _elapsed: Duration,
+    ) {
+    }
+    /// The connection is about to be dropped, `elapsed` counts from when it was opened
+    fn connection_closed(&self, _peer_addr: Option<SocketAddr>, _elapsed: Duration) {}
+}
+
+impl fmt::Debug for dyn Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hooks")
+    }
+}
+
+/// How much is printed while serving, errors are always printed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// No access log
+    Quiet,
+    /// Access log
+    #[default]
+    Normal,
+    /// Also every request line as it's parsed
+    Verbose,
+    /// Also connections opening and closing, and the path each request resolves to
+    Debug,
+}
+
+/// Format of the access log line printed for every response
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Apache Common Log Format
+    #[default]
+    Common,
+    /// Common Log Format followed by the Referer and User-Agent
+    Combined,
+    /// Don't log requests
+    None,
+}
+
+/// How to answer a request with a trailing slash that names a file, such as `/file.txt/`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TrailingSlashPolicy {
+    /// Respond with 404
+    #[default]
+    Strict,
+    /// Redirect to the path without the trailing slash
+    Redirect,
+}
+
+/// Parsed from `name:regex=/path`, e.g. `bot:.*bot.*=/bot-index.html`
+#[derive(Clone, Debug)]
+pub struct UaRule {
+    pub name: String,
+    pub pattern: Regex,
+    pub path: String,
+}
+
+impl FromStr for UaRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s.split_once(':').ok_or("expected name:regex=/path")?;
+        let (pattern, path) = rest.rsplit_once('=').ok_or("expected name:regex=/path")?;
+        if !path.starts_with('/') {
+            return Err(format!("path '{}' must start with '/'", path));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            pattern: Regex::new(pattern).map_err(|err| err.to_string())?,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Canned answer for `/`, parsed from `text:<body>` or `status:<code>`
+#[derive(Clone, Debug)]
+pub enum RootResponse {
+    /// `200` with this plain text body
+    Text(String),
+    /// Full status, such as `204 No Content`
+    Status(String),
+}
+
+impl FromStr for RootResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("text", text)) => Ok(RootResponse::Text(text.to_string())),
+            Some(("status", code)) => {
+                let code: u16 = code
+                    .parse()
+                    .ok()
+                    .filter(|v| (100..600).contains(v))
+                    .ok_or_else(|| format!("invalid status code '{}'", code))?;
+                Ok(RootResponse::Status(format!("{} {}", code, reason_phrase(code))))
+            }
+            _ => Err("expected text:<body> or status:<code>".to_string()),
+        }
+    }
+}
 
 #[derive(Debug)]
 struct State {
-    root: PathBuf,
+    config: Config,
+    cache: Option<Mutex<FileCache>>,
+    exclude: RegexSet,
+}
+
+impl State {
+    fn is_compress_excluded(&self, path: &Path) -> bool {
+        let Some(ext) = path.extension().and_then(|v| v.to_str()) else {
+            return false;
+        };
+        self.config
+            .no_compress_ext
+            .iter()
+            .any(|v| v.trim_start_matches('.').eq_ignore_ascii_case(ext))
+    }
+
+    fn is_ext_allowed(&self, path: &Path) -> bool {
+        if self.config.allow_ext.is_empty() {
+            return true;
+        }
+        let Some(ext) = path.extension().and_then(|v| v.to_str()) else {
+            return false;
+        };
+        self.config
+            .allow_ext
+            .iter()
+            .any(|v| v.trim_start_matches('.').eq_ignore_ascii_case(ext))
+    }
+
+    // Prefix every request is expected to have, without the trailing slash. Empty when serving from `/`
+    fn base_path(&self) -> &str {
+        self.config.base_path.trim_end_matches('/')
+    }
+
+    // Only the requested name itself counts, so files under a directory such as `.well-known` can still be fetched
+    // directly. The directory is hidden from listings and has none of its own
+    fn is_hidden(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.config.root).unwrap_or(path);
+        let dotfile = relative
+            .file_name()
+            .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."));
+        (self.config.no_dotfiles && dotfile)
+            || (!self.exclude.is_empty() && self.exclude.is_match(&relative.to_string_lossy()))
+    }
+
+    // `Cache-Control` value for files
+    fn cache_control(&self) -> String {
+        match self.config.cache_max_age {
+            0 => "no-cache".to_string(),
+            secs => format!("public, max-age={}", secs),
+        }
+    }
+
+    // Every body built in memory, instead of streamed, must be checked against this
+    fn fits_in_memory(&self, len: usize) -> bool {
+        self.config.max_memory_response.is_none_or(|max| len <= max)
+    }
 }
 
+/// Serves `root` on `address` with the default options until `cancel` fires. Shorthand for [`serve`]
 pub async fn run(address: &str, root: PathBuf, cancel: CancellationToken) -> io::Result<()> {
+    serve(Config::new(root).addresses([address]), cancel).await
+}
+
+/// Serves `config` on `addresses`, replacing the ones it has. Shorthand for [`serve`]
+pub async fn run_on(addresses: &[&str], config: Config, cancel: CancellationToken) -> io::Result<()> {
+    serve(config.addresses(addresses.iter().copied()), cancel).await
+}
+
+/// Serves `config` on every one of its addresses until `cancel` fires, then waits for open connections to finish
+pub async fn serve(config: Config, cancel: CancellationToken) -> io::Result<()> {
     let (sender, mut wg) = mpsc::channel::<()>(1);
-    let listener = TcpListener::bind(address).await?;
-    let state = Arc::new(State { root });
+    let mut resolved = Vec::new();
+    for address in &config.addresses {
+        resolved.extend(tokio::net::lookup_host(address).await?);
+    }
+    // On Linux an IPv6 wildcard also takes the IPv4 port by default, which would make binding both fail
+    let v6_only = resolved.iter().any(|v| v.is_ipv4());
+    let listeners = resolved
+        .into_iter()
+        .map(|v| bind(v, v6_only))
+        .collect::<io::Result<Vec<_>>>()?;
+    // Certificates are read before dropping privileges, they are usually only readable by root
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls(cert, key)?),
+        (None, None) => None,
+        _ => return Err(Error::other("tls_cert and tls_key must be set together")),
+    };
+    #[cfg(unix)]
+    let config = privileges::drop_privileges(config)?;
+    #[cfg(not(unix))]
+    if config.user.is_some() || config.group.is_some() || config.chroot {
+        return Err(Error::other("dropping privileges is only supported on unix"));
+    }
+    let connections = match config.max_connections {
+        0 => Semaphore::MAX_PERMITS,
+        v => v,
+    };
+    // Shared by every listener, the limit is for the whole server
+    let connections = Arc::new(Semaphore::new(connections));
+    let cache = (config.cache_size > 0).then(|| Mutex::new(FileCache::new(config.cache_size)));
+    // Read after chrooting, the file is under root either way
+    let exclude = load_excludes(&config)?;
+    let state = Arc::new(State { config, cache, exclude });
+    let mut accepting = JoinSet::new();
+    for listener in listeners {
+        let accept = accept_loop(
+            listener,
+            state.clone(),
+            tls.clone(),
+            connections.clone(),
+            sender.clone(),
+        );
+        accepting.spawn(accept);
+    }
     select! {
-        err = async {
-            loop {
-                let sender = sender.clone();
-                let state = state.clone();
-                let (stream, _) = listener.accept().await?;
-                tokio::spawn(async move {
-                    handle_stream(stream, &state).await;
-                    drop(sender);
-                });
-            }
-            #[allow(unreachable_code)]
-            // We need this line or rust can't reason about the return value
-            Ok::<_, io::Error>(())
-        } => err?,
+        Some(result) = accepting.join_next() => result.map_err(Error::other)??,
         _ = cancel.cancelled() => {},
     }
+    // Stops the remaining listeners, connections already accepted are left to finish
+    accepting.shutdown().await;
     drop(sender);
-    // We want to use this as a 'WaitGroup', so ignore the error
-    let _ = wg.recv().await;
-    Ok(())
+    // We want to use this as a 'WaitGroup', so ignore the error. Connections still open after the timeout are dropped
+    // along with the runtime, or keep going if it outlives us
+    match state.config.shutdown_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, wg.recv()).await.map(|_| ()).map_err(|_| {
+            Error::new(
+                io::ErrorKind::TimedOut,
+                "connections still open after the shutdown timeout",
+            )
+        }),
+        None => {
+            let _ = wg.recv().await;
+            Ok(())
+        }
+    }
+}
+
+fn load_excludes(config: &Config) -> io::Result<RegexSet> {
+    let mut globs = config.exclude.clone();
+    match std::fs::read_to_string(config.root.join(".httpfsignore")) {
+        Ok(content) => {
+            // The list of what's hidden shouldn't itself be served
+            globs.push("/.httpfsignore".to_string());
+            globs.extend(content.lines().filter(|v| !v.starts_with('#')).map(str::to_string));
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let patterns = globs
+        .iter()
+        .map(|v| v.trim())
+        .filter(|v| !v.trim_matches('/').is_empty())
+        .map(glob_to_regex);
+    RegexSet::new(patterns).map_err(Error::other)
+}
+
+// Works like gitignore: a pattern without a slash matches a name at any depth, one with a slash is anchored at root, and
+// everything under a match is excluded with it. `*` and `?` stay within a name, `**` crosses directories
+fn glob_to_regex(glob: &str) -> String {
+    let glob = glob.trim_end_matches('/');
+    let (anchored, glob) = match glob.strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (glob.contains('/'), glob),
+    };
+    let mut re = String::from(if anchored { "^" } else { "(?:^|/)" });
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.next_if_eq(&'*').is_some() => match chars.next_if_eq(&'/') {
+                Some(_) => re.push_str("(?:.*/)?"),
+                None => re.push_str(".*"),
+            },
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push_str("(?:/|$)");
+    re
+}
+
+fn bind(address: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    if v6_only && address.is_ipv6() {
+        use std::os::fd::AsRawFd;
+        let on: libc::c_int = 1;
+        // SAFETY: the socket is open and `on` outlives the call
+        let r = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                &on as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if r != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = v6_only;
+    socket.bind(address)?;
+    socket.listen(1024)
+}
+
+// Only returns when accepting fails, or after the first connection that got a response with `once`
+async fn accept_loop(
+    listener: TcpListener,
+    state: Arc<State>,
+    tls: Option<TlsAcceptor>,
+    connections: Arc<Semaphore>,
+    sender: mpsc::Sender<()>,
+) -> io::Result<()> {
+    let once = state.config.once;
+    loop {
+        let sender = sender.clone();
+        let state = state.clone();
+        let tls = tls.clone();
+        // Past the limit, new clients wait in the listen backlog until a connection finishes
+        let permit = connections.clone().acquire_owned().await.map_err(Error::other)?;
+        let (stream, peer_addr) = listener.accept().await?;
+        let connection = tokio::spawn(async move {
+            let secure = tls.is_some();
+            let handler = match tls {
+                Some(acceptor) => {
+                    // The handshake gets the same time budget as reading a request
+                    let handshake = acceptor.accept(stream);
+                    let stream = match state.config.read_timeout {
+                        Some(v) => tokio::time::timeout(v, handshake)
+                            .await
+                            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+                        None => handshake.await,
+                    };
+                    stream.ok().map(|v| HttpHandler::new(Box::new(v) as Box<dyn Stream>))
+                }
+                None => Some(HttpHandler::new(Box::new(stream) as Box<dyn Stream>)),
+            };
+            let served = match handler {
+                Some(mut handler) => {
+                    handler.peer_addr = Some(peer_addr);
+                    handler.secure = secure;
+                    handle_stream(handler, &state).await
+                }
+                None => false,
+            };
+            drop(permit);
+            drop(sender);
+            served
+        });
+        // A failed handshake or a client leaving before sending anything doesn't use up the one connection
+        if once && connection.await.unwrap_or(false) {
+            return Ok(());
+        }
+    }
+}
+
+fn load_tls(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let pem_error = |path: &Path, err| Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err));
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|v| v.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| pem_error(cert, err))?;
+    let key_der = PrivateKeyDer::from_pem_file(key).map_err(|err| pem_error(key, err))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key_der)
+        .map_err(|err| Error::new(io::ErrorKind::InvalidInput, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Whether any request got a response, even if only an error for a malformed one
+async fn handle_stream(mut handler: HttpHandler, state: &State) -> bool {
+    handler.read_timeout = state.config.read_timeout;
+    handler.max_request_line = state.config.max_request_line;
+    handler.max_headers_size = state.config.max_headers_size;
+    handler.allow_http09 = state.config.allow_http09;
+    handler.strict = state.config.strict;
+    handler.rate_limit = state.config.rate_limit;
+    if state.config.verbosity >= Verbosity::Debug {
+        eprintln!("connection from {} opened", peer_name(&handler));
+    }
+    let served = match &state.config.hooks {
+        Some(hooks) => {
+            let opened_at = Instant::now();
+            hooks.connection_opened(handler.peer_addr);
+            let served = serve_requests(&mut handler, state).await;
+            hooks.connection_closed(handler.peer_addr, opened_at.elapsed());
+            served
+        }
+        None => serve_requests(&mut handler, state).await,
+    };
+    if state.config.verbosity >= Verbosity::Debug {
+        eprintln!("connection from {} closed", peer_name(&handler));
+    }
+    served
+}
+
+fn peer_name(handler: &HttpHandler) -> String {
+    handler.peer_addr.map_or("-".to_string(), |v| v.to_string())
 }
 
-async fn handle_stream(stream: TcpStream, state: &State) {
-    let mut handler = HttpHandler::new(stream);
-    handle_request(&mut handler, state).await.unwrap();
-    handler.stream.flush().await.unwrap();
+// Whether any request got a response
+async fn serve_requests(handler: &mut HttpHandler, state: &State) -> bool {
+    let mut served = false;
+    loop {
+        let result = handle_request(handler, state).await;
+        served |= handler.response_status != 0;
+        if let Err(err) = result.and(handler.stream.flush().await) {
+            if !is_disconnect(&err) {
+                eprintln!("connection error: {}", err);
+            }
+            return served;
+        }
+        if !handler.keep_open {
+            return served;
+        }
+    }
+}
+
+// Only once there is a response to write, a closed or idle connection isn't held up any longer than it already is
+async fn delay_response(state: &State) {
+    if let Some(delay) = state.config.response_delay {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Clients going away mid-response is business as usual, not something worth reporting
+fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
 }
 
 async fn handle_request(handler: &mut HttpHandler, state: &State) -> io::Result<()> {
-    let mut options = ResponseOptions::default();
-    let Ok(request_line) = handler.read_request_line().await else {
-        return handler.write_status("400 Bad Request", &options).await;
+    let options = ResponseOptions {
+        keep_alive_after_error: state.config.keep_alive_after_error,
+        strip_headers: &state.config.strip_headers,
+        server: state.config.server_header.as_deref(),
+        ..Default::default()
+    };
+    let request = handler.read_request().await;
+    let parsed_at = Instant::now();
+    let request = match request {
+        Ok(v) => v,
+        Err(HttpError::Io(err)) => return Err(err),
+        Err(err) => match err.status() {
+            Some(status) => {
+                delay_response(state).await;
+                let result = handler.write_status(status, &[], &options).await;
+                log_access(handler, None, state);
+                call_response_hook(handler, None, state);
+                return result;
+            }
+            None => return Ok(()),
+        },
+    };
+    delay_response(state).await;
+    if let Some(hooks) = &state.config.hooks {
+        hooks.request_received(handler.peer_addr, &request);
+    }
+    if state.config.verbosity >= Verbosity::Verbose {
+        eprintln!(
+            "request from {}: {} {} {}",
+            peer_name(handler),
+            request.line.method.as_str(),
+            request.line.uri,
+            request.line.version.as_str(),
+        );
+    }
+    // Only uploads read the body, anything larger than what's worth discarding is refused before reading any of it
+    let reads_body = request.line.method == Method::Put && state.config.enable_upload;
+    if !reads_body && handler.unread_body() > state.config.discard_limit {
+        let options = ResponseOptions {
+            version: request.line.version,
+            omit_body: request.line.method == Method::Head,
+            ..options
+        };
+        let result = handler.write_status("413 Content Too Large", &[], &options).await;
+        log_access(handler, Some(&request), state);
+        call_response_hook(handler, Some(&request), state);
+        return result;
+    }
+    // The deadline counts from the first byte of the request, waiting for it on an idle connection is `read_timeout`'s
+    // business
+    let result = match state.config.request_deadline {
+        Some(deadline) => {
+            let at = handler.received_at.unwrap_or(parsed_at) + deadline;
+            match tokio::time::timeout_at(at, respond(handler, &request, state, options)).await {
+                Ok(result) => result,
+                // The response may be half written, so the only thing left to do is dropping the connection
+                Err(_) => {
+                    let message = format!("request exceeded deadline of {:?}, closing connection", deadline);
+                    return Err(Error::new(io::ErrorKind::TimedOut, message));
+                }
+            }
+        }
+        None => respond(handler, &request, state, options).await,
+    };
+    if result.is_ok() {
+        skip_unread_body(handler, &request, state).await?;
+    }
+    log_access(handler, Some(&request), state);
+    call_response_hook(handler, Some(&request), state);
+    if let (Some(threshold), Some(received_at)) = (state.config.log_slow, handler.received_at) {
+        let total = received_at.elapsed();
+        if total >= threshold {
+            eprintln!(
+                "slow request: {} {} took {:?} (parse {:?}, respond {:?})",
+                request.line.method.as_str(),
+                request.line.uri,
+                total,
+                parsed_at - received_at,
+                parsed_at.elapsed(),
+            );
+        }
+    }
+    result
+}
+
+// Whatever body the response didn't read would otherwise be parsed as the next request. Large ones aren't worth reading
+// just to throw them away, and a client waiting on `Expect: 100-continue` may never send it, so those connections are
+// closed instead
+async fn skip_unread_body(handler: &mut HttpHandler, request: &Request, state: &State) -> io::Result<()> {
+    if !handler.keep_open || handler.unread_body() == 0 {
+        return Ok(());
+    }
+    let expects_continue = request.headers.get("Expect").is_some();
+    if expects_continue || handler.unread_body() > state.config.discard_limit {
+        handler.keep_open = false;
+        return Ok(());
+    }
+    handler.discard_body().await
+}
+
+fn call_response_hook(handler: &HttpHandler, request: Option<&Request>, state: &State) {
+    let Some(hooks) = &state.config.hooks else {
+        return;
     };
+    if handler.response_status == 0 {
+        return;
+    }
+    let elapsed = handler.received_at.map_or(Duration::ZERO, |v| v.elapsed());
+    hooks.response_sent(
+        handler.peer_addr,
+        request,
+        handler.response_status,
+        handler.response_bytes,
+        elapsed,
+    );
+}
 
-    options.omit_body = match request_line.method {
-        Method::Get => false,
-        Method::Head => true,
-        _ => return handler.write_status("405 Method Not Allowed", &options).await,
+// `request` is `None` when it couldn't be parsed, it's logged as `-` like Apache does
+fn log_access(handler: &HttpHandler, request: Option<&Request>, state: &State) {
+    if state.config.log_format == LogFormat::None
+        || state.config.verbosity == Verbosity::Quiet
+        || handler.response_status == 0
+    {
+        return;
+    }
+    let host = handler.peer_addr.map_or("-".to_string(), |v| v.ip().to_string());
+    // `Wed, 14 Oct 2026 05:03:54 GMT` is rearranged into `14/Oct/2026:05:03:54 +0000`
+    let date = httpdate::fmt_http_date(SystemTime::now());
+    let date = match date.split(' ').collect::<Vec<_>>()[..] {
+        [_, day, month, year, time, _] => format!("{}/{}/{}:{} +0000", day, month, year, time),
+        _ => date,
     };
+    let request_line = request.map_or("-".to_string(), |v| {
+        format!("{} {} {}", v.line.method.as_str(), v.line.uri, v.line.version.as_str())
+    });
+    let bytes = match handler.response_bytes {
+        0 => "-".to_string(),
+        n => n.to_string(),
+    };
+    let mut line = format!(
+        "{} - - [{}] \"{}\" {} {}",
+        host,
+        date,
+        escape_log(&request_line),
+        handler.response_status,
+        bytes
+    );
+    if state.config.log_format == LogFormat::Combined {
+        let header = |name| request.and_then(|v| v.headers.get(name)).unwrap_or("-");
+        line.push_str(&format!(
+            " \"{}\" \"{}\"",
+            escape_log(header("Referer")),
+            escape_log(header("User-Agent"))
+        ));
+    }
+    println!("{}", line);
+}
+
+// Quotes and control characters come from the client, they must not be able to forge log fields or lines
+fn escape_log(s: &str) -> String {
+    s.chars().flat_map(char::escape_default).collect()
+}
+
+async fn respond(
+    handler: &mut HttpHandler,
+    request: &Request,
+    state: &State,
+    mut options: ResponseOptions<'_>,
+) -> io::Result<()> {
+    let request_line = &request.line;
+    options.version = request_line.version;
+    // HEAD gets whatever GET would, early errors and redirects included, only without the body
+    options.omit_body = request_line.method == Method::Head;
+    // A one-shot server answers one request only
+    let keepalive = !state.config.disable_keepalive && !state.config.once;
     options.keep_open = match request_line.version {
-        HttpVersion::Http1_0 => false,
-        HttpVersion::Http1_1 => true,
-        _ => return handler.write_status("505 HTTP Version Not Supported", &options).await,
+        // 1.0 closes by default, unless the client opts in. Bodies of unknown length close the connection anyway
+        HttpVersion::Http1_0 => keepalive && has_connection_token(&request.headers, "keep-alive"),
+        HttpVersion::Http1_1 => keepalive && !has_connection_token(&request.headers, "close"),
+        HttpVersion::Http0_9 => false,
+    };
+    options.gzip = !state.config.disable_compression
+        && (handler.secure || !state.config.compress_tls_only)
+        && accepts_encoding(&request.headers, "gzip");
+    let cors = cors_headers(&request.headers, state);
+    let options = ResponseOptions {
+        extra_headers: &cors,
+        ..options
+    };
+    // Preflights never carry credentials, they have to go through for CORS to work at all
+    let is_preflight = request_line.method == Method::Options && !state.config.cors.is_empty();
+    if let Some(credentials) = &state.config.auth {
+        if !is_preflight && !is_authorized(&request.headers, credentials) {
+            let headers = [("WWW-Authenticate", "Basic realm=\"httpfs\"")];
+            return handler.write_status("401 Unauthorized", &headers, &options).await;
+        }
+    }
+    // Browsers don't send fragments, but nothing stops other clients from doing it
+    let uri = request_line
+        .uri
+        .split_once('#')
+        .map_or(request_line.uri.as_str(), |(uri, _)| uri);
+    let uri = strip_absolute_form(uri);
+    let uri = uri.as_ref();
+    // Besides absolute-form, only origin-form (`/path`) and asterisk-form (`*`) make sense for a server
+    if !uri.starts_with('/') && uri != "*" {
+        return handler.write_status("400 Bad Request", &[], &options).await;
+    }
+    // Everything outside the base path isn't ours, the base itself is a directory so it gets its trailing slash
+    let base = state.base_path();
+    let uri = match uri.strip_prefix(base) {
+        _ if base.is_empty() || uri == "*" => uri,
+        Some(rest) if rest.starts_with('/') => rest,
+        Some(rest) if rest.is_empty() || rest.starts_with('?') => {
+            let location = format!("{}/{}", base, rest);
+            let headers = [("Location", location.as_str())];
+            return handler.write_status("301 Moved Permanently", &headers, &options).await;
+        }
+        _ => return write_not_found(handler, state, &options).await,
+    };
+    let (path_uri, query) = uri.split_once('?').unwrap_or((uri, ""));
+    for route in &state.config.routes {
+        if route.method == request_line.method
+            && path_uri.starts_with(&route.prefix)
+            && (route.handler)(request, handler, &options).await?
+        {
+            return Ok(());
+        }
+    }
+    if let Some(VirtualPaths(virtual_paths)) = &state.config.virtual_paths {
+        if let Some((status, body, ctype)) = virtual_paths(request_line) {
+            return handler.write_buffer(&status, body, &ctype, &[], &options).await;
+        }
+    }
+
+    let allow = if state.config.enable_upload {
+        "GET, HEAD, OPTIONS, PUT, DELETE"
+    } else {
+        "GET, HEAD, OPTIONS"
+    };
+    // `*` names the server itself, only OPTIONS can be asked about it
+    if path_uri == "*" && request_line.method != Method::Options {
+        return handler.write_status("400 Bad Request", &[], &options).await;
+    }
+    match request_line.method {
+        Method::Get | Method::Head => {}
+        // Both the server wide `OPTIONS *` and per resource ones get the same answer, every resource allows the same
+        Method::Options => {
+            let mut headers = vec![("Allow", allow)];
+            if is_preflight {
+                headers.push(("Access-Control-Allow-Methods", allow));
+                if let Some(v) = request.headers.get("Access-Control-Request-Headers") {
+                    headers.push(("Access-Control-Allow-Headers", v));
+                }
+            }
+            return handler.write_empty("204 No Content", &headers, &options).await;
+        }
+        Method::Put if state.config.enable_upload => {
+            return handle_upload(handler, request, path_uri, state, &options).await;
+        }
+        Method::Delete if state.config.enable_upload => {
+            return handle_delete(handler, path_uri, state, &options).await;
+        }
+        Method::Delete => return handler.write_status("403 Forbidden", &[], &options).await,
+        // Methods we know of but never serve, as opposed to ones the path doesn't allow
+        Method::Post | Method::Connect | Method::Trace | Method::Patch => {
+            return handler.write_status("501 Not Implemented", &[], &options).await;
+        }
+        Method::Put => {
+            let headers = [("Allow", allow)];
+            return handler.write_status("405 Method Not Allowed", &headers, &options).await;
+        }
+    };
+    // The built-in files step aside when the directory has real ones
+    if path_uri == "/robots.txt" && !state.config.root.join("robots.txt").is_file() {
+        if let Some(robots) = &state.config.robots {
+            let body = robots.clone().into_bytes();
+            return handler
+                .write_buffer("200 Ok", body, "text/plain; charset=utf-8", &[], &options)
+                .await;
+        }
+    }
+    if path_uri == "/sitemap.xml" && state.config.generate_sitemap && !state.config.root.join("sitemap.xml").is_file() {
+        let host = request.headers.get("Host").unwrap_or("localhost");
+        return match get_sitemap_body(host, state).await {
+            Ok(body) => {
+                handler
+                    .write_buffer("200 Ok", body, "application/xml; charset=utf-8", &[], &options)
+                    .await
+            }
+            Err(_) => handler.write_status("500 Internal Server Error", &[], &options).await,
+        };
+    }
+    match &state.config.root_response {
+        Some(RootResponse::Text(text)) if path_uri == "/" => {
+            let body = text.clone().into_bytes();
+            return handler
+                .write_buffer("200 Ok", body, "text/plain; charset=utf-8", &[], &options)
+                .await;
+        }
+        Some(RootResponse::Status(status)) if path_uri == "/" => {
+            // These can't have a body, not even the status text
+            if matches!(status.as_bytes()[0], b'1') || status.starts_with("204") || status.starts_with("304") {
+                return handler.write_empty(status, &[], &options).await;
+            }
+            return handler.write_status(status, &[], &options).await;
+        }
+        _ => {}
+    }
+    let mut path_uri = path_uri;
+    let vary_ua: Vec<_>;
+    let options = if path_uri == "/" && !state.config.ua_rules.is_empty() {
+        let user_agent = request.headers.get("User-Agent").unwrap_or("");
+        if let Some(rule) = state.config.ua_rules.iter().find(|v| v.pattern.is_match(user_agent)) {
+            path_uri = &rule.path;
+        }
+        // Whichever variant is served, a shared cache must not hand it to clients with another User-Agent
+        vary_ua = [options.extra_headers, &[("Vary", "User-Agent")]].concat();
+        ResponseOptions {
+            extra_headers: &vary_ua,
+            ..options
+        }
+    } else {
+        options
     };
-    match handle_path(handler, &request_line.uri, state, &options).await {
+    match handle_path(handler, request, path_uri, query, state, &options).await {
         Ok(r) => r,
-        Err(_) => handler.write_status("500 Internal Server Error", &options).await,
+        Err(_) => handler.write_status("500 Internal Server Error", &[], &options).await,
+    }
+}
+
+// Tells apart the temporary files of uploads in progress
+static UPLOAD_ID: AtomicU64 = AtomicU64::new(0);
+
+async fn handle_upload(
+    handler: &mut HttpHandler,
+    request: &Request,
+    path_uri: &str,
+    state: &State,
+    options: &ResponseOptions<'_>,
+) -> io::Result<()> {
+    // Chunked uploads aren't supported, the length has to be known upfront
+    let Some(len) = request.headers.get("Content-Length") else {
+        return handler.write_status("411 Length Required", &[], options).await;
+    };
+    let Ok(len) = len.parse::<u64>() else {
+        return handler.write_status("400 Bad Request", &[], options).await;
+    };
+    // Refused before anything touches the disk. Since the body is left unread the connection can't be reused
+    if state.config.max_upload_size.is_some_and(|max| len > max) {
+        let options = ResponseOptions {
+            keep_open: false,
+            ..*options
+        };
+        return handler.write_status("413 Content Too Large", &[], &options).await;
+    }
+    let path = match parse_new_path(path_uri, state).await {
+        Ok(v) => v,
+        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+            return handler.write_status("400 Bad Request", &[], options).await;
+        }
+        Err(_) => return handler.write_status("403 Forbidden", &[], options).await,
+    };
+    if !state.is_ext_allowed(&path) || state.is_hidden(&path) {
+        return handler.write_status("403 Forbidden", &[], options).await;
+    }
+    if path_uri.ends_with('/') || path.is_dir() {
+        return handler.write_status("409 Conflict", &[], options).await;
+    }
+    let existed = path.is_file();
+    // Fails when some parent is a file already
+    let Some(parent) = path.parent() else {
+        return handler.write_status("409 Conflict", &[], options).await;
+    };
+    if tokio::fs::create_dir_all(parent).await.is_err() {
+        return handler.write_status("409 Conflict", &[], options).await;
+    }
+    // The body goes to a temporary file first, so an interrupted upload doesn't leave a truncated file behind
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    // Concurrent uploads of the same file each get their own, the last one to finish replaces it
+    let id = UPLOAD_ID.fetch_add(1, Ordering::Relaxed);
+    let partial = path.with_file_name(format!(".{}.{}-{}.part", name, std::process::id(), id));
+    let mut file = OpenOptions::new().write(true).create_new(true).open(&partial).await?;
+    if options.version == HttpVersion::Http1_1
+        && request
+            .headers
+            .get("Expect")
+            .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+    {
+        handler.write_continue().await?;
+    }
+    let written = async {
+        handler.read_body(len, &mut file).await?;
+        file.flush().await?;
+        tokio::fs::rename(&partial, &path).await
+    };
+    if let Err(err) = written.await {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(err);
+    }
+    if existed {
+        return handler.write_empty("204 No Content", &[], options).await;
+    }
+    let location = format!("{}{}", state.base_path(), path_uri);
+    let headers = [("Location", location.as_str())];
+    handler.write_status("201 Created", &headers, options).await
+}
+
+async fn handle_delete(
+    handler: &mut HttpHandler,
+    path_uri: &str,
+    state: &State,
+    options: &ResponseOptions<'_>,
+) -> io::Result<()> {
+    // Resolved without following the last component, so deleting a symlink removes the link and not its target
+    let path = match parse_new_path(path_uri, state).await {
+        Ok(v) => v,
+        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+            return handler.write_status("400 Bad Request", &[], options).await;
+        }
+        Err(_) => return handler.write_status("403 Forbidden", &[], options).await,
+    };
+    let Ok(metadata) = path.symlink_metadata() else {
+        return handler.write_status("404 Not Found", &[], options).await;
+    };
+    if state.is_hidden(&path) {
+        return handler.write_status("404 Not Found", &[], options).await;
+    }
+    if path == state.config.root || (!metadata.is_dir() && !state.is_ext_allowed(&path)) {
+        return handler.write_status("403 Forbidden", &[], options).await;
     }
+    let removed = if metadata.is_dir() {
+        tokio::fs::remove_dir(&path).await
+    } else {
+        tokio::fs::remove_file(&path).await
+    };
+    match removed {
+        Ok(()) => handler.write_empty("204 No Content", &[], options).await,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => handler.write_status("404 Not Found", &[], options).await,
+        // Most likely a directory that isn't empty
+        Err(_) => handler.write_status("409 Conflict", &[], options).await,
+    }
+}
+
+// Uses `404.html` from root as the body when there is one. The page is read directly instead of going through
+// `handle_path`, so a missing or unreadable one falls back to the plain status rather than looking for itself again
+async fn write_not_found(handler: &mut HttpHandler, state: &State, options: &ResponseOptions<'_>) -> io::Result<()> {
+    if let Ok(path) = parse_path("/404.html", state).await {
+        let fits = tokio::fs::metadata(&path)
+            .await
+            .is_ok_and(|v| v.is_file() && state.fits_in_memory(v.len() as usize));
+        if fits {
+            if let Ok(body) = tokio::fs::read(&path).await {
+                return handler
+                    .write_buffer("404 Not Found", body, "text/html; charset=utf-8", &[], options)
+                    .await;
+            }
+        }
+    }
+    handler.write_status("404 Not Found", &[], options).await
 }
 
 // Outer result is for internal errors, inner is for connection errors
 async fn handle_path(
     handler: &mut HttpHandler,
+    request: &Request,
     path_uri: &str,
+    query: &str,
     state: &State,
-    options: &ResponseOptions,
+    options: &ResponseOptions<'_>,
 ) -> io::Result<io::Result<()>> {
-    let Ok(path) = parse_path(&path_uri, &state.root).await else {
-        return Ok(handler.write_status("404 Not Found", &options).await);
+    let mut resolved = parse_path(path_uri, state).await;
+    match &resolved {
+        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+            return Ok(handler.write_status("400 Bad Request", &[], options).await);
+        }
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            return Ok(handler.write_status("403 Forbidden", &[], options).await);
+        }
+        _ => {}
+    }
+    // Set when we serve something other than what was asked for
+    let mut content_location = None;
+    if resolved.is_err() && state.config.try_html_extension && !path_uri.ends_with('/') {
+        // parse_path is applied to the retried path too, so it can't escape root either
+        let retry = format!("{}.html", path_uri);
+        resolved = parse_path(&retry, state).await;
+        content_location = resolved.is_ok().then(|| format!("{}{}", state.base_path(), retry));
+    }
+    // Client side routes don't exist on disk, the app's entry point handles them. Only browser navigations get it,
+    // missing assets are still reported as missing
+    if resolved.is_err()
+        && state.config.spa
+        && request.line.method == Method::Get
+        && accepts_media(&request.headers, "text/html")
+    {
+        for name in &state.config.index_files {
+            match parse_path(&format!("/{}", name), state).await {
+                Ok(index) if index.is_file() => {
+                    resolved = Ok(index);
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    let Ok(mut path) = resolved else {
+        if state.config.trailing_slash_policy == TrailingSlashPolicy::Redirect && path_uri.ends_with('/') {
+            let trimmed = path_uri.trim_end_matches('/');
+            if matches!(parse_path(trimmed, state).await, Ok(p) if p.is_file()) {
+                let location = if query.is_empty() {
+                    format!("{}{}", state.base_path(), trimmed)
+                } else {
+                    format!("{}{}?{}", state.base_path(), trimmed, query)
+                };
+                let headers = [("Location", location.as_str())];
+                return Ok(handler.write_status("301 Moved Permanently", &headers, options).await);
+            }
+        }
+        return Ok(write_not_found(handler, state, options).await);
     };
+    if (!path.is_dir() && !state.is_ext_allowed(&path)) || state.is_hidden(&path) {
+        return Ok(write_not_found(handler, state, options).await);
+    }
+    if state.config.verbosity >= Verbosity::Debug {
+        eprintln!("{} resolved to {}", path_uri, path.display());
+    }
     if path.is_dir() {
-        let body = get_folder_body(path, path_uri).await?;
-        Ok(handler.write_buffer("200 Ok", body, "text/html", options).await)
-    } else {
-        let (file, mime, len) = get_file_data(&path).await?;
-        Ok(handler
-            .write_reader("200 Ok", file, mime.essence_str(), len, options)
+        // Relative links, both in listings and index files, only resolve correctly under the trailing slash
+        if !path_uri.ends_with('/') {
+            let location = if query.is_empty() {
+                format!("{}{}/", state.base_path(), path_uri)
+            } else {
+                format!("{}{}/?{}", state.base_path(), path_uri, query)
+            };
+            let headers = [("Location", location.as_str())];
+            return Ok(handler.write_status("301 Moved Permanently", &headers, options).await);
+        }
+        // Candidates resolve like any other requested file, a symlinked index is followed but can't leave root
+        let mut index = None;
+        for name in &state.config.index_files {
+            match parse_path(&format!("{}{}", path_uri, name), state).await {
+                Ok(candidate) if candidate.is_file() && state.is_ext_allowed(&candidate) => {
+                    index = Some(candidate);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        match index {
+            Some(index) => path = index,
+            None => {
+                if state.config.list_root_only && path != state.config.root {
+                    return Ok(handler.write_status("403 Forbidden", &[], options).await);
+                }
+                // Listings change whenever the directory does and have no validators, always revalidate them
+                let headers = [("Cache-Control", "no-cache"), ("Vary", "Accept")];
+                let json = accepts_media(&request.headers, "application/json");
+                if json {
+                    let body = get_folder_json(path, state).await?;
+                    return Ok(handler
+                        .write_buffer("200 Ok", body, "application/json; charset=utf-8", &headers, options)
+                        .await);
+                }
+                // HTTP/1.0 has no chunked encoding, without it the length must be known upfront
+                if options.version == HttpVersion::Http1_0 {
+                    let body = get_folder_body(path, path_uri, state).await?;
+                    return Ok(handler
+                        .write_buffer("200 Ok", body, "text/html; charset=utf-8", &headers, options)
+                        .await);
+                }
+                // The streamed listing has no length, so its headers don't depend on the body and walking the
+                // directory for one that is thrown away is wasted work. The buffered ones above need it for theirs
+                if options.omit_body {
+                    return Ok(handler
+                        .write_chunked(
+                            "200 Ok",
+                            tokio::io::empty(),
+                            "text/html; charset=utf-8",
+                            &headers,
+                            options,
+                        )
+                        .await);
+                }
+                return stream_folder_body(handler, path, path_uri, state, &headers, options).await;
+            }
+        }
+    }
+    let cache_control = state.cache_control();
+    let mut headers = vec![("Cache-Control", cache_control.as_str())];
+    if let Some(v) = &content_location {
+        headers.push(("Content-Location", v.as_str()));
+    }
+    let (mut body, mime, mut len, mut modified) = get_file_body(&path, options.omit_body, state).await?;
+    // The sidecar replaces the body and its validators, the type is still the original file's
+    let sidecar_options;
+    let options = match open_sidecar(&path, &request.headers, state).await {
+        Some((sidecar, coding, sidecar_len, sidecar_modified)) => {
+            (body, len, modified) = (FileBody::Open(sidecar), sidecar_len, sidecar_modified);
+            sidecar_options = ResponseOptions {
+                gzip: false,
+                content_encoding: Some(coding),
+                ..*options
+            };
+            &sidecar_options
+        }
+        None => options,
+    };
+    // Decided before any validator is sent, compressing weakens the ETag
+    let uncompressed_options;
+    let options = match options.gzip && (len < state.config.compress_min_size || state.is_compress_excluded(&path)) {
+        true => {
+            uncompressed_options = ResponseOptions {
+                gzip: false,
+                ..*options
+            };
+            &uncompressed_options
+        }
+        false => options,
+    };
+    let last_modified = modified.map(httpdate::fmt_http_date);
+    if let Some(v) = &last_modified {
+        headers.push(("Last-Modified", v.as_str()));
+    }
+    let pretty = query.split('&').any(|v| v == "pretty=1");
+    // Too large files are sent as they are, reformatting them would mean holding them in memory
+    let reformat = mime == mime_guess::mime::APPLICATION_JSON
+        && (pretty || state.config.minify_json)
+        && options.content_encoding.is_none()
+        && state.fits_in_memory(len);
+    // The reformatted document isn't the file byte for byte, so the file's validator can only be a weak one for it
+    let etag = match etag(len, modified) {
+        Some(v) if reformat => Some(format!("W/{}", v)),
+        v => v,
+    };
+    if let Some(v) = &etag {
+        headers.push(("ETag", v.as_str()));
+    }
+    let ctype = content_type(&mime);
+    if is_not_modified(&request.headers, modified, etag.as_deref()) {
+        // The full response would be compressed, which weakens its ETag. The 304 must repeat the same validator
+        let weak = etag
+            .as_ref()
+            .filter(|v| !v.starts_with("W/") && options.gzip && is_compressible(&ctype));
+        let weak = weak.map(|v| format!("W/{}", v));
+        if let Some(weak) = &weak {
+            headers.iter_mut().filter(|v| v.0 == "ETag").for_each(|v| v.1 = weak);
+        }
+        return Ok(handler.write_empty("304 Not Modified", &headers, options).await);
+    }
+    // Interim responses only exist since HTTP/1.1
+    if !state.config.early_hint_links.is_empty()
+        && options.version == HttpVersion::Http1_1
+        && mime == mime_guess::mime::TEXT_HTML
+    {
+        handler.write_early_hints(&state.config.early_hint_links).await?;
+    }
+    if reformat {
+        // Even without a body the length of the reformatted document has to be known
+        let mut file = match body {
+            FileBody::Open(file) => file,
+            FileBody::Cached(body) => {
+                let body = reformat_json(&body, pretty).unwrap_or_else(|| body.to_vec());
+                return Ok(handler.write_buffer("200 Ok", body, &ctype, &headers, options).await);
+            }
+            FileBody::Stat => open_contained(&path, &state.config.root).await?,
+        };
+        let mut body = Vec::with_capacity(len);
+        file.read_to_end(&mut body).await?;
+        let body = reformat_json(&body, pretty).unwrap_or(body);
+        return Ok(handler.write_buffer("200 Ok", body, &ctype, &headers, options).await);
+    }
+    headers.push(("Accept-Ranges", "bytes"));
+    // Range handling is only defined for GET
+    let range = match request.line.method {
+        Method::Get if is_range_current(&request.headers, modified, etag.as_deref()) => {
+            parse_range(request.headers.get("Range"), len)
+        }
+        _ => ByteRange::Full,
+    };
+    let content_range;
+    match range {
+        ByteRange::Full => Ok(send_file(handler, "200 Ok", body, 0, len, &ctype, &headers, options).await),
+        ByteRange::Partial(start, end) => {
+            content_range = format!("bytes {}-{}/{}", start, end, len);
+            headers.push(("Content-Range", &content_range));
+            let clen = end - start + 1;
+            // The range applies to the file itself, compressing the slice would make it meaningless
+            let options = &ResponseOptions {
+                gzip: false,
+                ..*options
+            };
+            Ok(send_file(
+                handler,
+                "206 Partial Content",
+                body,
+                start as u64,
+                clen,
+                &ctype,
+                &headers,
+                options,
+            )
             .await)
+        }
+        ByteRange::Unsatisfiable => {
+            content_range = format!("bytes */{}", len);
+            headers.push(("Content-Range", &content_range));
+            Ok(handler
+                .write_status("416 Range Not Satisfiable", &headers, options)
+                .await)
+        }
     }
 }
 
-async fn parse_path(path_uri: &str, root: &PathBuf) -> io::Result<PathBuf> {
-    let mut path = path_uri;
-    if path.starts_with('/') {
-        path = &path[1..];
+// If-None-Match takes precedence, If-Modified-Since is only looked at when it's absent (RFC 9110 13.2.2)
+fn is_not_modified(headers: &Headers, modified: Option<SystemTime>, etag: Option<&str>) -> bool {
+    if let Some(tags) = headers.get("If-None-Match") {
+        let Some(etag) = etag else {
+            return false;
+        };
+        // If-None-Match uses the weak comparison, so the W/ prefix is ignored on both sides
+        let etag = etag.trim_start_matches("W/");
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
     }
-    let path = root.join(path).canonicalize()?;
-    if !path.starts_with(root) {
-        return Err(Error::other("invalid path"));
+    let (Some(modified), Some(since)) = (modified, headers.get("If-Modified-Since")) else {
+        return false;
+    };
+    let Ok(since) = httpdate::parse_http_date(since) else {
+        return false;
+    };
+    // HTTP dates have no sub-second part, so the mtime has to be truncated the same way before comparing
+    SystemTime::from(httpdate::HttpDate::from(modified)) <= since
+}
+
+// If-Range makes the range conditional, a file that changed since the client got its first part is sent whole so
+// the pieces aren't stitched together from different versions. Unlike If-None-Match the comparison is the strong one,
+// and a date only counts when it's exactly the file's mtime (RFC 9110 13.1.5)
+fn is_range_current(headers: &Headers, modified: Option<SystemTime>, etag: Option<&str>) -> bool {
+    let Some(validator) = headers.get("If-Range").map(str::trim) else {
+        return true;
+    };
+    if validator.starts_with('"') || validator.starts_with("W/") {
+        return !validator.starts_with("W/") && etag == Some(validator);
+    }
+    match (modified, httpdate::parse_http_date(validator)) {
+        (Some(modified), Ok(date)) => SystemTime::from(httpdate::HttpDate::from(modified)) == date,
+        _ => false,
     }
-    Ok(path)
 }
 
-async fn get_folder_body(dir: PathBuf, path_uri: &str) -> io::Result<Vec<u8>> {
-    let mut rd = read_dir(dir).await?;
-    let mut buf = Vec::with_capacity(1024);
-    write!(
-        buf,
-        "<html><head><title>Directory listing for {0}</title><head><body><h1>Directory listing for {0}</h1><hr><ul>",
-        path_uri
-    )?;
-    while let Some(d) = rd.next_entry().await? {
-        let is_dir = d.file_type().await?.is_dir();
-        write!(
-            buf,
-            "<li><a href=\"{0}{1}\">{0}</li>",
-            d.file_name().to_str().unwrap(),
-            if is_dir { "/" } else { "" }
-        )?;
+enum ByteRange {
+    Full,
+    /// Inclusive bounds, already clamped to the file
+    Partial(usize, usize),
+    Unsatisfiable,
+}
+
+// Only a single range is supported, anything we don't understand falls back to sending the whole file, which is
+// always a valid answer to a range request
+fn parse_range(header: Option<&str>, len: usize) -> ByteRange {
+    let Some(spec) = header.and_then(|v| v.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let parse = |v: &str| v.parse::<usize>().ok();
+    match (start, end) {
+        ("", suffix) => match parse(suffix) {
+            Some(0) => ByteRange::Unsatisfiable,
+            Some(_) if len == 0 => ByteRange::Unsatisfiable,
+            Some(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+            None => ByteRange::Full,
+        },
+        (start, "") => match parse(start) {
+            Some(start) if start >= len => ByteRange::Unsatisfiable,
+            Some(start) => ByteRange::Partial(start, len - 1),
+            None => ByteRange::Full,
+        },
+        (start, end) => match (parse(start), parse(end)) {
+            (Some(start), Some(end)) if start > end => ByteRange::Full,
+            (Some(start), Some(_)) if start >= len => ByteRange::Unsatisfiable,
+            (Some(start), Some(end)) => ByteRange::Partial(start, end.min(len - 1)),
+            _ => ByteRange::Full,
+        },
     }
-    write!(buf, "</ul><hr></body></html>")?;
-    Ok(buf)
 }
 
-async fn get_file_data(path: &PathBuf) -> io::Result<(File, Mime, usize)> {
-    let file = OpenOptions::new().read(true).open(&path).await?;
-    let meta = file.metadata().await?;
-    let mime = mime_guess::from_path(path).first_or(mime_guess::mime::APPLICATION_OCTET_STREAM);
-    Ok((file, mime, meta.len() as usize))
+fn is_authorized(headers: &Headers, credentials: &str) -> bool {
+    let Some(encoded) = headers.get("Authorization").and_then(|v| {
+        let (scheme, rest) = v.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("Basic").then_some(rest)
+    }) else {
+        return false;
+    };
+    let Some(decoded) = decode_base64(encoded) else {
+        return false;
+    };
+    constant_time_eq(&decoded, credentials.as_bytes())
+}
+
+// Standard alphabet, padding is optional and whitespace anywhere is skipped
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for b in input.bytes().filter(|v| !v.is_ascii_whitespace()) {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// Takes the same time wherever the first difference is, so the credentials can't be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= (x ^ y) as usize;
+    }
+    diff == 0
+}
+
+// Echoes the request's Origin when it's allowed, unless every origin is
+fn cors_headers<'a>(headers: &'a Headers, state: &'a State) -> Vec<(&'static str, &'a str)> {
+    let allowed = &state.config.cors;
+    if allowed.is_empty() {
+        return Vec::new();
+    }
+    // Repeated lines are read as a single comma separated list
+    let expose = state
+        .config
+        .cors_expose_headers
+        .iter()
+        .map(|v| ("Access-Control-Expose-Headers", v.as_str()));
+    if allowed.iter().any(|v| v == "*") {
+        return [("Access-Control-Allow-Origin", "*")]
+            .into_iter()
+            .chain(expose)
+            .collect();
+    }
+    // The response depends on the Origin even when it isn't allowed, caches must know that
+    let mut cors = vec![("Vary", "Origin")];
+    if let Some(origin) = headers.get("Origin").filter(|v| allowed.iter().any(|a| a == v)) {
+        cors.push(("Access-Control-Allow-Origin", origin));
+        cors.extend(expose);
+    }
+    cors
+}
+
+fn accepts_encoding(headers: &Headers, coding: &str) -> bool {
+    headers.get("Accept-Encoding").is_some_and(|v| {
+        v.split(',').any(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next().unwrap_or("");
+            // An explicit q=0 means the client refuses the coding
+            let refused = params.any(|v| v.strip_prefix("q=").is_some_and(|q| q.parse() == Ok(0.0)));
+            (name.eq_ignore_ascii_case(coding) || name == "*") && !refused
+        })
+    })
+}
+
+// Only an explicit mention of `media` counts, nearly every client accepts `*/*` but expects something specific
+fn accepts_media(headers: &Headers, media: &str) -> bool {
+    headers.get("Accept").is_some_and(|v| {
+        v.split(',').any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let name = params.next().unwrap_or("");
+            let refused = params.any(|v| v.strip_prefix("q=").is_some_and(|q| q.parse() == Ok(0.0)));
+            name.eq_ignore_ascii_case(media) && !refused
+        })
+    })
+}
+
+// `http://host/path?query` becomes `/path?query`, which proxies send and servers must accept (RFC 9112 3.2.2)
+fn strip_absolute_form(uri: &str) -> Cow<'_, str> {
+    let Some((scheme, rest)) = uri.split_once("://") else {
+        return Cow::Borrowed(uri);
+    };
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return Cow::Borrowed(uri);
+    }
+    // An empty path means the root, even when there is a query
+    match rest.find(['/', '?']) {
+        Some(i) if rest.as_bytes()[i] == b'/' => Cow::Borrowed(&rest[i..]),
+        Some(i) => Cow::Owned(format!("/{}", &rest[i..])),
+        None => Cow::Borrowed("/"),
+    }
+}
+
+fn has_connection_token(headers: &Headers, token: &str) -> bool {
+    headers
+        .get("Connection")
+        .is_some_and(|v| v.split(',').any(|v| v.trim().eq_ignore_ascii_case(token)))
+}
+
+// The containment check only holds at the time of the call, a component could be swapped for a symlink before the
+// path is actually opened. Files are opened through `open_contained`, which verifies the file it ends up opening.
+async fn parse_path(path_uri: &str, state: &State) -> io::Result<PathBuf> {
+    let root = &state.config.root;
+    let path = decode_path(path_uri, state)?;
+    // Decoding happens first, so an encoded `..` is caught like a literal one. Checking before canonicalize means
+    // escaping to a path that doesn't exist isn't reported as missing
+    if escapes_root(&path) {
+        return Err(Error::new(io::ErrorKind::PermissionDenied, "path escapes root"));
+    }
+    // A symlink can still point outside root, that only shows once it's resolved
+    let path = root.join(path).canonicalize()?;
+    if !path.starts_with(root) {
+        return Err(Error::new(io::ErrorKind::PermissionDenied, "path escapes root"));
+    }
+    Ok(path)
+}
+
+// Like `parse_path`, but the file doesn't need to exist yet. `..` is refused outright, and the deepest part that does
+// exist must resolve under root, so a symlink can't be used to write somewhere else
+async fn parse_new_path(path_uri: &str, state: &State) -> io::Result<PathBuf> {
+    let root = &state.config.root;
+    let path = decode_path(path_uri, state)?;
+    if !path
+        .components()
+        .all(|v| matches!(v, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(Error::new(io::ErrorKind::PermissionDenied, "path escapes root"));
+    }
+    let path = root.join(path);
+    let existing = path
+        .ancestors()
+        .find(|v| v.symlink_metadata().is_ok())
+        .unwrap_or(root)
+        .canonicalize()?;
+    if !existing.starts_with(root) {
+        return Err(Error::new(io::ErrorKind::PermissionDenied, "path escapes root"));
+    }
+    Ok(path)
+}
+
+// Whether applying `..` components ever takes the path above where it starts
+fn escapes_root(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir if depth == 0 => return true,
+            Component::ParentDir => depth -= 1,
+            Component::RootDir | Component::Prefix(_) => return true,
+            Component::CurDir => {}
+        }
+    }
+    false
+}
+
+// Decodes the path part of the uri into a path relative to root
+fn decode_path(path_uri: &str, state: &State) -> io::Result<PathBuf> {
+    let path = path_uri.strip_prefix('/').unwrap_or(path_uri);
+    let path = percent_decode(path).ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "malformed escape"))?;
+    let path = bytes_to_path(path)?;
+    if let Some(max) = state.config.max_path_depth {
+        if path_depth(&path) > max {
+            return Err(Error::new(io::ErrorKind::InvalidInput, "path too deep"));
+        }
+    }
+    Ok(path)
+}
+
+// Depth the path ends up at once `.` and `..` are applied, checked before touching the filesystem. The deepest point
+// reached counts, so going down and back up can't be used to get around the limit
+fn path_depth(path: &Path) -> usize {
+    let (mut depth, mut deepest) = (0usize, 0);
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        deepest = deepest.max(depth);
+    }
+    deepest
+}
+
+// Fails on a `%` not followed by two hex digits
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        let hi = (bytes.next()? as char).to_digit(16)?;
+        let lo = (bytes.next()? as char).to_digit(16)?;
+        out.push((hi * 16 + lo) as u8);
+    }
+    Some(out)
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(std::ffi::OsString::from_vec(bytes).into())
+}
+
+// Other platforms don't have byte paths, so the decoded name must at least be valid UTF-8
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| Error::new(io::ErrorKind::InvalidInput, "path is not valid utf-8"))
+}
+
+async fn get_folder_body(dir: PathBuf, path_uri: &str, state: &State) -> io::Result<Vec<u8>> {
+    let entries = read_listing(dir, state).await?;
+    let mut buf = Vec::with_capacity(1024);
+    write_listing_head(&mut buf, path_uri, state)?;
+    for entry in &entries {
+        write_listing_row(&mut buf, entry)?;
+        if !state.fits_in_memory(buf.len()) {
+            return Err(Error::other("listing exceeds the in-memory response limit"));
+        }
+    }
+    buf.extend_from_slice(LISTING_FOOTER);
+    Ok(buf)
+}
+
+// Sends the listing a row at a time with chunked encoding, so the HTML for every entry is never held at once. Entries
+// still have to be read upfront for sorting, an error there can be answered with a 500 before anything is sent
+async fn stream_folder_body(
+    handler: &mut HttpHandler,
+    dir: PathBuf,
+    path_uri: &str,
+    state: &State,
+    headers: &[(&str, &str)],
+    options: &ResponseOptions<'_>,
+) -> io::Result<io::Result<()>> {
+    let entries = read_listing(dir, state).await?;
+    let (mut tx, rx) = tokio::io::duplex(16 * 1024);
+    let produce = async move {
+        let mut buf = Vec::with_capacity(1024);
+        write_listing_head(&mut buf, path_uri, state)?;
+        tx.write_all(&buf).await?;
+        for entry in &entries {
+            buf.clear();
+            write_listing_row(&mut buf, entry)?;
+            tx.write_all(&buf).await?;
+        }
+        tx.write_all(LISTING_FOOTER).await
+    };
+    // If the response stops early, `rx` is dropped and the writes above fail, so this can't hang. The reverse can't
+    // happen, `produce` only fails because of that
+    let (_, written) = tokio::join!(
+        produce,
+        handler.write_chunked("200 Ok", rx, "text/html; charset=utf-8", headers, options)
+    );
+    Ok(written)
+}
+
+const LISTING_FOOTER: &[u8] = b"</table><hr></body></html>";
+
+fn write_listing_head(buf: &mut Vec<u8>, path_uri: &str, state: &State) -> io::Result<()> {
+    write!(
+        buf,
+        "<html><head><title>Directory listing for {0}</title><head><body><h1>Directory listing for {0}</h1>",
+        escape_html(&format!("{}{}", state.base_path(), path_uri))
+    )?;
+    if state.config.listing_breadcrumbs {
+        write_breadcrumbs(buf, path_uri, state.base_path())?;
+    }
+    write!(buf, "<hr><table><tr><th>Name</th><th>Size</th><th>Modified</th></tr>")?;
+    if path_uri != "/" {
+        write!(buf, "<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>")?;
+    }
+    Ok(())
+}
+
+fn write_listing_row(buf: &mut Vec<u8>, entry: &ListingEntry) -> io::Result<()> {
+    // The name is escaped differently for the link target and for display, it can't be reused as is for either.
+    // Names aren't necessarily UTF-8, the link keeps the raw bytes so it still resolves
+    let slash = if entry.is_dir { "/" } else { "" };
+    let size = if entry.is_dir {
+        "-".to_string()
+    } else {
+        format_size(entry.size)
+    };
+    let modified = entry.modified.map(httpdate::fmt_http_date).unwrap_or_default();
+    write!(
+        buf,
+        "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td>{}</td><td>{}</td></tr>",
+        percent_encode(entry.name.as_encoded_bytes()),
+        escape_html(&entry.name.to_string_lossy()),
+        size,
+        modified,
+    )
+}
+
+// `[{"name": ..., "type": "file" | "dir", "size": ..., "modified": ...}]`, with `modified` in seconds since the epoch
+async fn get_folder_json(dir: PathBuf, state: &State) -> io::Result<Vec<u8>> {
+    let entries = read_listing(dir, state).await?;
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            let modified = entry
+                .modified
+                .and_then(|v| v.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|v| v.as_secs());
+            serde_json::json!({
+                "name": entry.name.to_string_lossy(),
+                "type": if entry.is_dir { "dir" } else { "file" },
+                "size": entry.size,
+                "modified": modified,
+            })
+        })
+        .collect();
+    let body = serde_json::to_vec(&entries).map_err(Error::other)?;
+    if !state.fits_in_memory(body.len()) {
+        return Err(Error::other("listing exceeds the in-memory response limit"));
+    }
+    Ok(body)
+}
+
+struct ListingEntry {
+    name: OsString,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+// Entries that should show up in a listing of `dir`, directories first and then by name. Everything has to be read
+// before sorting, and each entry costs a stat for its metadata
+async fn read_listing(dir: PathBuf, state: &State) -> io::Result<Vec<ListingEntry>> {
+    let mut rd = read_dir(dir).await?;
+    let mut entries = Vec::new();
+    while let Some(d) = rd.next_entry().await? {
+        let path = d.path();
+        // Symlinks are shown as what they point to, falling back to the link itself when it's dangling
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(v) => v,
+            Err(_) => d.metadata().await?,
+        };
+        let is_dir = metadata.is_dir();
+        if (!is_dir && !state.is_ext_allowed(&path)) || state.is_hidden(&path) {
+            continue;
+        }
+        entries.push(ListingEntry {
+            name: d.file_name(),
+            is_dir,
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    entries.sort_by(|a, b| {
+        b.is_dir
+            .cmp(&a.is_dir)
+            .then_with(|| {
+                a.name
+                    .to_string_lossy()
+                    .to_lowercase()
+                    .cmp(&b.name.to_string_lossy().to_lowercase())
+            })
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(entries)
+}
+
+// Binary units with one decimal, like `ls -h`
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// Lists every HTML file under root, sorted so the output is stable. Symlinks are not followed
+async fn get_sitemap_body(host: &str, state: &State) -> io::Result<Vec<u8>> {
+    let mut pages = Vec::new();
+    let mut dirs = vec![state.config.root.clone()];
+    while let Some(dir) = dirs.pop() {
+        let mut rd = read_dir(dir).await?;
+        while let Some(d) = rd.next_entry().await? {
+            let file_type = d.file_type().await?;
+            let path = d.path();
+            if state.is_hidden(&path) {
+                continue;
+            } else if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file()
+                && path.extension().is_some_and(|v| v.eq_ignore_ascii_case("html"))
+                && state.is_ext_allowed(&path)
+            {
+                pages.push(path);
+            }
+        }
+    }
+    pages.sort();
+    let mut buf = Vec::with_capacity(1024);
+    writeln!(buf, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(buf, "<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">")?;
+    for page in pages {
+        let Ok(relative) = page.strip_prefix(&state.config.root) else {
+            continue;
+        };
+        let href = percent_encode(relative.as_os_str().as_encoded_bytes());
+        writeln!(
+            buf,
+            "<url><loc>http://{}{}/{}</loc></url>",
+            escape_html(host),
+            escape_html(state.base_path()),
+            href
+        )?;
+        if !state.fits_in_memory(buf.len()) {
+            return Err(Error::other("sitemap exceeds the in-memory response limit"));
+        }
+    }
+    writeln!(buf, "</urlset>")?;
+    Ok(buf)
+}
+
+// Links every ancestor of `path_uri`, the directory itself is the last, non-linked, segment. Links are under `base`
+fn write_breadcrumbs(buf: &mut Vec<u8>, path_uri: &str, base: &str) -> io::Result<()> {
+    let segments: Vec<&str> = path_uri.split('/').filter(|v| !v.is_empty()).collect();
+    let Some((current, ancestors)) = segments.split_last() else {
+        return write!(buf, "<nav>Home /</nav>");
+    };
+    let mut href = format!("{}/", base);
+    write!(buf, "<nav><a href=\"{}\">Home</a>", escape_html(&href))?;
+    for segment in ancestors {
+        href.push_str(segment);
+        href.push('/');
+        write!(
+            buf,
+            " / <a href=\"{}\">{}</a>",
+            escape_html(&href),
+            escape_html(segment)
+        )?;
+    }
+    write!(buf, " / {} /</nav>", escape_html(current))
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Escapes everything but unreserved characters and `/`, so the result can be used as a URL path
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~' | b'/') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+// Returns `None` if the input isn't valid JSON, in which case it should be served untouched
+fn reformat_json(input: &[u8], pretty: bool) -> Option<Vec<u8>> {
+    let value: serde_json::Value = serde_json::from_slice(input).ok()?;
+    if pretty {
+        serde_json::to_vec_pretty(&value).ok()
+    } else {
+        serde_json::to_vec(&value).ok()
+    }
+}
+
+async fn get_file_data(path: &Path, state: &State) -> io::Result<(File, Mime, usize, Option<SystemTime>)> {
+    let mut file = open_contained(path, &state.config.root).await?;
+    let meta = file.metadata().await?;
+    let mime = get_file_mime(path, Some(&mut file), state).await?;
+    Ok((file, mime, meta.len() as usize, meta.modified().ok()))
+}
+
+// Same as `get_file_data` without opening the file, unless its type has to be sniffed
+async fn get_file_meta(path: &Path, state: &State) -> io::Result<(Mime, usize, Option<SystemTime>)> {
+    let meta = stat_contained(path).await?;
+    let mime = get_file_mime(path, None, state).await?;
+    Ok((mime, meta.len() as usize, meta.modified().ok()))
+}
+
+async fn get_file_mime(path: &Path, file: Option<&mut File>, state: &State) -> io::Result<Mime> {
+    let ext = path.extension().and_then(|v| v.to_str()).map(str::to_ascii_lowercase);
+    let mime = match ext.and_then(|v| state.config.mime_types.get(&v)) {
+        Some(mime) => mime.clone(),
+        None => mime_guess::from_path(path).first_or(mime_guess::mime::APPLICATION_OCTET_STREAM),
+    };
+    if !state.config.magic_sniff || mime != mime_guess::mime::APPLICATION_OCTET_STREAM {
+        return Ok(mime);
+    }
+    let sniffed = match file {
+        Some(file) => sniff_mime(file).await?,
+        None => sniff_mime(&mut open_contained(path, &state.config.root).await?).await?,
+    };
+    Ok(sniffed.unwrap_or(mime))
+}
+
+async fn stat_contained(path: &Path) -> io::Result<std::fs::Metadata> {
+    let meta = tokio::fs::symlink_metadata(path).await?;
+    // The canonical path has no symlinks, so one appearing means it was swapped under us
+    if meta.file_type().is_symlink() {
+        return Err(Error::other("file was replaced by a symlink"));
+    }
+    Ok(meta)
+}
+
+// Where the body of a file response comes from
+enum FileBody {
+    Open(File),
+    Cached(Arc<[u8]>),
+    /// Bodyless responses only stat the file, only its length goes out
+    Stat,
+}
+
+// Small files go through the cache when it's enabled. Otherwise bodyless responses only need the metadata, the file
+// is opened lazily if something still has to read it
+async fn get_file_body(
+    path: &Path,
+    omit_body: bool,
+    state: &State,
+) -> io::Result<(FileBody, Mime, usize, Option<SystemTime>)> {
+    if let Some(cache) = &state.cache {
+        let meta = stat_contained(path).await?;
+        let (len, modified) = (meta.len() as usize, meta.modified().ok());
+        let cached = cache.lock().unwrap().get(path, len, modified);
+        if let Some(cached) = cached {
+            return Ok((FileBody::Cached(cached.body), cached.mime, len, modified));
+        }
+        if len <= state.config.cache_file_size && !omit_body {
+            let (file, mime, len, modified) = get_file_data(path, state).await?;
+            // Bounded by the length the validators were computed from, a file growing meanwhile is caught next time
+            let mut body = Vec::with_capacity(len);
+            file.take(len as u64).read_to_end(&mut body).await?;
+            let body: Arc<[u8]> = body.into();
+            let cached = CachedFile {
+                body: body.clone(),
+                mime: mime.clone(),
+                modified,
+            };
+            cache.lock().unwrap().insert(path.to_path_buf(), cached);
+            let len = body.len();
+            return Ok((FileBody::Cached(body), mime, len, modified));
+        }
+    }
+    if omit_body {
+        let (mime, len, modified) = get_file_meta(path, state).await?;
+        return Ok((FileBody::Stat, mime, len, modified));
+    }
+    let (file, mime, len, modified) = get_file_data(path, state).await?;
+    Ok((FileBody::Open(file), mime, len, modified))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_file(
+    handler: &mut HttpHandler,
+    status: &str,
+    body: FileBody,
+    offset: u64,
+    len: usize,
+    ctype: &str,
+    headers: &[(&str, &str)],
+    options: &ResponseOptions<'_>,
+) -> io::Result<()> {
+    match body {
+        FileBody::Open(file) => {
+            handler
+                .write_file(status, file, offset, len, ctype, headers, options)
+                .await
+        }
+        FileBody::Cached(body) => {
+            let body = &body[offset as usize..offset as usize + len];
+            handler.write_reader(status, body, ctype, len, headers, options).await
+        }
+        FileBody::Stat => {
+            handler
+                .write_reader(status, tokio::io::empty(), ctype, len, headers, options)
+                .await
+        }
+    }
+}
+
+// Text, including the textual application types, is assumed to be UTF-8 unless it declares a charset. Browsers would
+// otherwise guess, and often get it wrong
+fn content_type(mime: &Mime) -> String {
+    if mime.get_param(mime_guess::mime::CHARSET).is_some() {
+        return mime.to_string();
+    }
+    let is_text = mime.type_() == mime_guess::mime::TEXT
+        || matches!(
+            mime.essence_str(),
+            "application/javascript" | "application/json" | "application/xml"
+        );
+    if is_text {
+        return format!("{}; charset=utf-8", mime.essence_str());
+    }
+    mime.essence_str().to_string()
+}
+
+// Looks for a precompressed `{path}.br` or `{path}.gz` next to the file, in that order, for codings the client accepts
+async fn open_sidecar(
+    path: &Path,
+    headers: &Headers,
+    state: &State,
+) -> Option<(File, &'static str, usize, Option<SystemTime>)> {
+    if state.config.disable_compression {
+        return None;
+    }
+    for (coding, suffix) in [("br", "br"), ("gzip", "gz")] {
+        if !accepts_encoding(headers, coding) {
+            continue;
+        }
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(suffix);
+        // Symlinked sidecars fail to open, like any other swapped path would
+        let Ok(file) = open_contained(Path::new(&sidecar), &state.config.root).await else {
+            continue;
+        };
+        match file.metadata().await {
+            Ok(meta) if meta.is_file() => return Some((file, coding, meta.len() as usize, meta.modified().ok())),
+            _ => continue,
+        }
+    }
+    None
+}
+
+// Changes whenever the file is rewritten, as long as its size or mtime second differ
+fn etag(len: usize, modified: Option<SystemTime>) -> Option<String> {
+    let secs = modified?.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs();
+    Some(format!("\"{}-{}\"", len, secs))
+}
+
+// Opens a path returned by `parse_path`, making sure the file opened is still inside `root`
+async fn open_contained(path: &Path, root: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    // The canonical path has no symlinks, so one appearing in the last component means it was swapped under us
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    let file = options.open(path).await?;
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        // Catches swapped parent directories too. /proc may be missing (e.g. after chrooting), in which case root is
+        // enforced by the kernel anyway
+        if let Ok(real) = std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())) {
+            if !real.starts_with(root) {
+                return Err(Error::other("opened file escapes root"));
+            }
+        }
+    }
+    Ok(file)
+}
+
+// Leaves the file positioned back at the start
+async fn sniff_mime(file: &mut File) -> io::Result<Option<Mime>> {
+    let mut header = Vec::with_capacity(512);
+    (&mut *file).take(512).read_to_end(&mut header).await?;
+    file.seek(SeekFrom::Start(0)).await?;
+    Ok(infer::get(&header).and_then(|v| v.mime_type().parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn print_config_redacts_auth_password() {
+        let mut config = Config::new("/srv/www".into());
+        config.auth = Some("bob:hunter2".to_string());
+        let printed = config.to_string();
+        assert!(printed.lines().any(|v| v == "root: /srv/www"));
+        assert!(printed.lines().any(|v| v == "auth: Some(\"bob:***\")"));
+        assert!(!printed.contains("hunter2"));
+    }
+
+    // An empty directory of its own for each test, canonical like the root `main` passes in
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("httpfs-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root.canonicalize().unwrap()
+    }
+
+    fn state_for(config: Config) -> State {
+        let exclude = load_excludes(&config).unwrap();
+        State {
+            config,
+            cache: None,
+            exclude,
+        }
+    }
+
+    // Serves a single connection that sends `input` and then closes its side, returning everything written back
+    async fn exchange(config: Config, input: &[u8]) -> String {
+        exchange_on(config, input, false).await
+    }
+
+    // Like `exchange`, with the connection taken for a TLS one when `secure` is set
+    async fn exchange_on(config: Config, input: &[u8], secure: bool) -> String {
+        let state = state_for(config);
+        let (mut client, server) = tokio::io::duplex(1024 * 1024);
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut handler = HttpHandler::new(Box::new(server) as Box<dyn Stream>);
+        handler.secure = secure;
+        handle_stream(handler, &state).await;
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_each_get_a_response() {
+        let root = temp_root("pipelining");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let request = "GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(Config::new(root), request.repeat(3).as_bytes()).await;
+        assert_eq!(output.matches("HTTP/1.1 200 Ok\r\n").count(), 3);
+        assert_eq!(output.matches("hello").count(), 3);
+    }
+
+    #[tokio::test]
+    async fn unread_body_is_skipped_before_the_next_request() {
+        let root = temp_root("smuggling");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let smuggled = "GET /secret HTTP/1.1\r\nHost: x\r\n\r\n";
+        let input = format!(
+            "GET /a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{}GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n",
+            smuggled.len(),
+            smuggled
+        );
+        let output = exchange(Config::new(root), input.as_bytes()).await;
+        assert_eq!(output.matches("HTTP/1.1 200 Ok\r\n").count(), 2);
+        assert!(!output.contains("404"));
+    }
+
+    #[tokio::test]
+    async fn body_over_the_discard_limit_closes_the_connection() {
+        let root = temp_root("discard-limit");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let input =
+            "GET /a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\n01234GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        let mut config = Config::new(root);
+        config.discard_limit = 4;
+        let output = exchange(config, input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        assert!(output.contains("Connection: close\r\n"));
+        assert_eq!(output.matches("HTTP/1.1 ").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn huge_body_is_refused_without_reading_it() {
+        let state = state_for(Config::new(temp_root("discard-huge")));
+        for (method, body) in [("GET", "413 Content Too Large"), ("HEAD", "")] {
+            // The client keeps its side open and never sends the body, reading it would never finish
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let input = format!(
+                "{} / HTTP/1.1\r\nHost: x\r\nContent-Length: 1000000000000\r\n\r\n",
+                method
+            );
+            client.write_all(input.as_bytes()).await.unwrap();
+            let serving = handle_stream(HttpHandler::new(Box::new(server) as Box<dyn Stream>), &state);
+            tokio::time::timeout(Duration::from_secs(5), serving).await.unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", output);
+            assert!(output.ends_with(&format!("\r\n\r\n{}", body)), "{}", output);
+        }
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        response
+            .lines()
+            .take_while(|v| !v.is_empty())
+            .find_map(|v| v.strip_prefix(name)?.strip_prefix(": "))
+    }
+
+    #[tokio::test]
+    async fn etag_is_stable_until_the_file_changes() {
+        let root = temp_root("etag");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let request = b"GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        let first = exchange(Config::new(root.clone()), request).await;
+        let second = exchange(Config::new(root.clone()), request).await;
+        let etag = header(&first, "ETag").unwrap();
+        assert!(etag.starts_with("\"5-") && etag.ends_with('"'), "{}", etag);
+        assert_eq!(header(&second, "ETag"), Some(etag));
+        std::fs::write(root.join("a.txt"), "hello again").unwrap();
+        let rewritten = exchange(Config::new(root), request).await;
+        assert_ne!(header(&rewritten, "ETag"), Some(etag));
+    }
+
+    #[tokio::test]
+    async fn etag_takes_precedence_over_the_date() {
+        let root = temp_root("precedence");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let output = exchange(Config::new(root.clone()), b"GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let etag = header(&output, "ETag").unwrap().to_string();
+        let epoch = "Thu, 01 Jan 1970 00:00:00 GMT";
+        let future = "Fri, 01 Jan 2100 00:00:00 GMT";
+
+        // The date alone would say modified
+        let input = format!(
+            "GET /a.txt HTTP/1.1\r\nHost: x\r\nIf-None-Match: {}\r\nIf-Modified-Since: {}\r\n\r\n",
+            etag, epoch
+        );
+        let output = exchange(Config::new(root.clone()), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+
+        // The date alone would say not modified
+        let input = format!(
+            "GET /a.txt HTTP/1.1\r\nHost: x\r\nIf-None-Match: \"other\"\r\nIf-Modified-Since: {}\r\n\r\n",
+            future
+        );
+        let output = exchange(Config::new(root), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
+
+    #[tokio::test]
+    async fn if_range_only_serves_the_range_for_the_current_file() {
+        let root = temp_root("if-range");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let output = exchange(Config::new(root.clone()), b"GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let etag = header(&output, "ETag").unwrap().to_string();
+        let modified = header(&output, "Last-Modified").unwrap().to_string();
+        let cases = [
+            (etag.clone(), "206 Partial Content"),
+            (modified, "206 Partial Content"),
+            ("\"other\"".to_string(), "200 Ok"),
+            (format!("W/{}", etag), "200 Ok"),
+            ("Thu, 01 Jan 1970 00:00:00 GMT".to_string(), "200 Ok"),
+        ];
+        for (validator, status) in cases {
+            let input = format!(
+                "GET /a.txt HTTP/1.1\r\nHost: x\r\nRange: bytes=0-1\r\nIf-Range: {}\r\n\r\n",
+                validator
+            );
+            let output = exchange(Config::new(root.clone()), input.as_bytes()).await;
+            assert!(
+                output.starts_with(&format!("HTTP/1.1 {}\r\n", status)),
+                "{}: {}",
+                validator,
+                output
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_upload_leaves_no_file_behind() {
+        let root = temp_root("upload-size");
+        let mut config = Config::new(root.clone());
+        config.enable_upload = true;
+        config.max_upload_size = Some(4);
+        let output = exchange(
+            config,
+            b"PUT /up.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\n0123456789",
+        )
+        .await;
+        assert!(output.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn listing_escapes_file_names() {
+        let root = temp_root("listing-escape");
+        std::fs::write(root.join("<script>&\"x'.txt"), "").unwrap();
+        let output = exchange(Config::new(root), b"GET / HTTP/1.0\r\n\r\n").await;
+        assert!(output.starts_with("HTTP/1.0 200 Ok\r\n"));
+        assert!(output.contains("href=\"%3Cscript%3E%26%22x%27.txt\""));
+        assert!(output.contains(">&lt;script&gt;&amp;&quot;x&#39;.txt</a>"));
+        assert!(!output.contains("<script>"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn listing_renders_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = temp_root("listing-non-utf8");
+        std::fs::write(root.join(std::ffi::OsStr::from_bytes(b"bad\xff.txt")), "").unwrap();
+        let output = exchange(Config::new(root), b"GET / HTTP/1.0\r\n\r\n").await;
+        assert!(output.starts_with("HTTP/1.0 200 Ok\r\n"));
+        assert!(output.contains("href=\"bad%FF.txt\""));
+        assert!(output.contains(">bad\u{FFFD}.txt</a>"));
+    }
+
+    #[tokio::test]
+    async fn head_sends_the_headers_without_a_body() {
+        let root = temp_root("head");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let output = exchange(
+            Config::new(root),
+            b"HEAD /a.txt HTTP/1.1\r\nHost: x\r\n\r\nHEAD /missing HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+        let (file, missing) = output.split_at(output.find("HTTP/1.1 404").unwrap());
+        assert!(file.starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert_eq!(header(file, "Content-Length"), Some("5"));
+        assert!(file.ends_with("\r\n\r\n"));
+        assert_eq!(header(missing, "Content-Length"), Some("13"));
+        assert!(missing.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn head_without_credentials_gets_no_body() {
+        let root = temp_root("auth-head");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let mut config = Config::new(root);
+        config.auth = Some("bob:hunter2".to_string());
+        config.keep_alive_after_error = true;
+        let input = "HEAD /a.txt HTTP/1.1\r\nHost: x\r\n\r\nGET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        let second = output.rfind("HTTP/1.1 401").unwrap();
+        assert!(output.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(output[..second].ends_with("\r\n\r\n"), "{}", output);
+        assert!(output.ends_with("\r\n\r\n401 Unauthorized"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn head_outside_the_base_path_gets_no_body() {
+        let mut config = Config::new(temp_root("base-path-head"));
+        config.base_path = "/files".to_string();
+        config.keep_alive_after_error = true;
+        config.route(Method::Head, "/api", |_request, handler, options| {
+            Box::pin(async move {
+                handler
+                    .write_buffer("200 Ok", b"{}".to_vec(), "application/json", &[], options)
+                    .await?;
+                Ok(true)
+            })
+        });
+        let input = "HEAD /files HTTP/1.1\r\nHost: x\r\n\r\nHEAD /other HTTP/1.1\r\nHost: x\r\n\r\nHEAD /files/api HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", output);
+        assert!(responses[0].starts_with("301 Moved Permanently\r\n"));
+        assert_eq!(header(responses[0], "Location"), Some("/files/"));
+        assert!(responses[1].starts_with("404 Not Found\r\n"));
+        assert!(responses[2].starts_with("200 Ok\r\n"));
+        assert_eq!(header(responses[2], "Content-Length"), Some("2"));
+        assert!(responses.iter().all(|v| v.ends_with("\r\n\r\n")), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn head_with_a_bad_target_gets_no_body() {
+        let mut config = Config::new(temp_root("target-head"));
+        config.keep_alive_after_error = true;
+        let input = "HEAD a.txt HTTP/1.1\r\nHost: x\r\n\r\nHEAD * HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 2, "{}", output);
+        for response in responses {
+            assert!(response.starts_with("400 Bad Request\r\n"));
+            assert!(response.ends_with("\r\n\r\n"), "{}", output);
+        }
+    }
+
+    #[tokio::test]
+    async fn not_modified_repeats_the_weak_etag_of_compressed_responses() {
+        let root = temp_root("gzip-etag");
+        std::fs::write(root.join("a.txt"), "hello ".repeat(1000)).unwrap();
+        let request = "GET /a.txt HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n";
+        let full = exchange(Config::new(root.clone()), format!("{}\r\n", request).as_bytes()).await;
+        assert_eq!(header(&full, "Content-Encoding"), Some("gzip"));
+        let etag = header(&full, "ETag").unwrap();
+        assert!(etag.starts_with("W/\""), "{}", etag);
+        let input = format!("{}If-None-Match: {}\r\n\r\n", request, etag);
+        let not_modified = exchange(Config::new(root), input.as_bytes()).await;
+        assert!(not_modified.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert_eq!(header(&not_modified, "ETag"), Some(etag));
+    }
+
+    #[tokio::test]
+    async fn ua_rules_pick_the_root_file_and_vary_on_the_user_agent() {
+        let root = temp_root("ua-rules");
+        std::fs::write(root.join("index.html"), "browser").unwrap();
+        std::fs::write(root.join("bot-index.html"), "bot").unwrap();
+        let mut config = Config::new(root.clone());
+        config.ua_rules = vec!["bot:(?i).*bot.*=/bot-index.html".parse().unwrap()];
+        let bot = exchange(
+            config,
+            b"GET / HTTP/1.1\r\nHost: x\r\nUser-Agent: Googlebot/2.1\r\n\r\n",
+        )
+        .await;
+        assert!(bot.ends_with("\r\n\r\nbot"), "{}", bot);
+        assert!(bot.contains("Vary: User-Agent\r\n"));
+
+        let mut config = Config::new(root);
+        config.ua_rules = vec!["bot:(?i).*bot.*=/bot-index.html".parse().unwrap()];
+        let browser = exchange(config, b"GET / HTTP/1.1\r\nHost: x\r\nUser-Agent: Firefox\r\n\r\n").await;
+        assert!(browser.ends_with("\r\n\r\nbrowser"), "{}", browser);
+        assert!(browser.contains("Vary: User-Agent\r\n"));
+    }
+
+    #[tokio::test]
+    async fn no_dotfiles_hides_dot_names_only() {
+        let root = temp_root("dotfiles");
+        std::fs::write(root.join(".env"), "secret").unwrap();
+        std::fs::create_dir(root.join(".well-known")).unwrap();
+        std::fs::write(root.join(".well-known/security.txt"), "contact").unwrap();
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let mut config = Config::new(root);
+        config.no_dotfiles = true;
+        config.keep_alive_after_error = true;
+        let input = "GET / HTTP/1.1\r\nHost: x\r\n\r\nGET /.env HTTP/1.1\r\nHost: x\r\n\r\nGET /.well-known/ HTTP/1.1\r\nHost: x\r\n\r\nGET /.well-known/security.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 4, "{}", output);
+        assert!(responses[0].starts_with("200 Ok\r\n") && responses[0].contains("a.txt"));
+        assert!(!responses[0].contains(".env") && !responses[0].contains(".well-known"));
+        assert!(responses[1].starts_with("404 Not Found\r\n"));
+        assert!(responses[2].starts_with("404 Not Found\r\n"));
+        assert!(responses[3].starts_with("200 Ok\r\n") && responses[3].ends_with("contact"));
+    }
+
+    #[tokio::test]
+    async fn unserved_methods_get_501_and_unknown_ones_400() {
+        let root = temp_root("methods");
+        let mut config = Config::new(root.clone());
+        config.keep_alive_after_error = true;
+        let input = "POST / HTTP/1.1\r\nHost: x\r\n\r\nPATCH / HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        assert_eq!(
+            output.matches("HTTP/1.1 501 Not Implemented\r\n").count(),
+            2,
+            "{}",
+            output
+        );
+        let output = exchange(Config::new(root), b"BREW / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(output.contains(" 400 Bad Request\r\n"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn reformatted_json_gets_a_weak_etag() {
+        let root = temp_root("json-etag");
+        std::fs::write(root.join("a.json"), "{ \"a\": 1 }").unwrap();
+        let plain = exchange(Config::new(root.clone()), b"GET /a.json HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let etag = header(&plain, "ETag").unwrap();
+        assert!(etag.starts_with('"'), "{}", etag);
+        let pretty = exchange(
+            Config::new(root.clone()),
+            b"GET /a.json?pretty=1 HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+        assert_eq!(header(&pretty, "ETag"), Some(format!("W/{}", etag).as_str()));
+        let mut config = Config::new(root);
+        config.minify_json = true;
+        let minified = exchange(config, b"GET /a.json HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(minified.ends_with("\r\n\r\n{\"a\":1}"), "{}", minified);
+        assert_eq!(header(&minified, "ETag"), Some(format!("W/{}", etag).as_str()));
+    }
+
+    #[tokio::test]
+    async fn only_files_over_the_minimum_size_are_compressed() {
+        let root = temp_root("compress-min-size");
+        std::fs::write(root.join("small.txt"), "hello").unwrap();
+        std::fs::write(root.join("large.txt"), "hello ".repeat(1000)).unwrap();
+        let request = |name| format!("GET /{} HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n", name);
+        let small = exchange(Config::new(root.clone()), request("small.txt").as_bytes()).await;
+        assert_eq!(header(&small, "Content-Encoding"), None);
+        assert!(small.ends_with("\r\n\r\nhello"));
+        let large = exchange(Config::new(root), request("large.txt").as_bytes()).await;
+        assert_eq!(header(&large, "Content-Encoding"), Some("gzip"));
+    }
+
+    #[tokio::test]
+    async fn cors_responses_expose_the_configured_headers() {
+        let root = temp_root("cors-expose");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let mut config = Config::new(root.clone());
+        config.cors = vec!["https://app.example".to_string()];
+        config.cors_expose_headers = vec!["ETag".to_string(), "Content-Range".to_string()];
+        let request = |origin| format!("GET /a.txt HTTP/1.1\r\nHost: x\r\nOrigin: {}\r\n\r\n", origin);
+        let output = exchange(config, request("https://app.example").as_bytes()).await;
+        assert_eq!(
+            header(&output, "Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+        assert!(
+            output.contains("Access-Control-Expose-Headers: ETag\r\nAccess-Control-Expose-Headers: Content-Range\r\n")
+        );
+
+        let mut config = Config::new(root);
+        config.cors = vec!["https://app.example".to_string()];
+        config.cors_expose_headers = vec!["ETag".to_string()];
+        let output = exchange(config, request("https://other.example").as_bytes()).await;
+        assert_eq!(header(&output, "Access-Control-Expose-Headers"), None);
+    }
+
+    #[tokio::test]
+    async fn excluded_extensions_are_not_compressed() {
+        let root = temp_root("no-compress-ext");
+        std::fs::write(root.join("app.min.js"), "a;".repeat(1000)).unwrap();
+        std::fs::write(root.join("a.txt"), "hello ".repeat(1000)).unwrap();
+        let request = |name| format!("GET /{} HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n", name);
+        let mut config = Config::new(root.clone());
+        config.no_compress_ext = vec![".JS".to_string()];
+        let excluded = exchange(config, request("app.min.js").as_bytes()).await;
+        assert_eq!(header(&excluded, "Content-Encoding"), None);
+        assert_eq!(header(&excluded, "Content-Length"), Some("2000"));
+        let mut config = Config::new(root);
+        config.no_compress_ext = vec![".JS".to_string()];
+        let text = exchange(config, request("a.txt").as_bytes()).await;
+        assert_eq!(header(&text, "Content-Encoding"), Some("gzip"));
+    }
+
+    #[tokio::test]
+    async fn compress_tls_only_skips_plain_connections() {
+        let root = temp_root("compress-tls-only");
+        std::fs::write(root.join("a.txt"), "hello ".repeat(1000)).unwrap();
+        let request = b"GET /a.txt HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n";
+        let mut config = Config::new(root.clone());
+        config.compress_tls_only = true;
+        let plain = exchange_on(config, request, false).await;
+        assert_eq!(header(&plain, "Content-Encoding"), None);
+        assert_eq!(header(&plain, "Content-Length"), Some("6000"));
+        let mut config = Config::new(root);
+        config.compress_tls_only = true;
+        let secure = exchange_on(config, request, true).await;
+        assert_eq!(header(&secure, "Content-Encoding"), Some("gzip"));
+    }
+
+    #[tokio::test]
+    async fn errors_close_the_connection_by_default() {
+        let root = temp_root("close-after-error");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let input = "GET /missing HTTP/1.1\r\nHost: x\r\n\r\nGET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(Config::new(root.clone()), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert_eq!(header(&output, "Connection"), Some("close"));
+        assert_eq!(output.matches("HTTP/1.1 ").count(), 1, "{}", output);
+
+        let mut config = Config::new(root);
+        config.keep_alive_after_error = true;
+        let output = exchange(config, input.as_bytes()).await;
+        assert_eq!(output.matches("HTTP/1.1 ").count(), 2, "{}", output);
+        assert!(output.ends_with("\r\n\r\nhello"));
+    }
+
+    #[tokio::test]
+    async fn response_delay_holds_back_the_response() {
+        let root = temp_root("response-delay");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let mut config = Config::new(root);
+        config.response_delay = Some(Duration::from_millis(200));
+        let started = Instant::now();
+        let output = exchange(config, b"GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(output.starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn files_swapped_after_resolving_are_not_opened() {
+        let root = temp_root("swap");
+        let outside = temp_root("swap-outside");
+        std::fs::write(outside.join("a.txt"), "secret").unwrap();
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/a.txt"), "hello").unwrap();
+        let state = state_for(Config::new(root.clone()));
+        let file = parse_path("/a.txt", &state).await.unwrap();
+        assert!(open_contained(&file, &root).await.is_ok());
+
+        std::fs::remove_file(&file).unwrap();
+        std::os::unix::fs::symlink(outside.join("a.txt"), &file).unwrap();
+        assert!(open_contained(&file, &root).await.is_err());
+        assert!(stat_contained(&file).await.is_err());
+        #[cfg(target_os = "linux")]
+        {
+            let nested = parse_path("/dir/a.txt", &state).await.unwrap();
+            std::fs::remove_dir_all(root.join("dir")).unwrap();
+            std::os::unix::fs::symlink(&outside, root.join("dir")).unwrap();
+            assert!(open_contained(&nested, &root).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn request_deadline_drops_slow_responses() {
+        let mut config = Config::new(temp_root("deadline"));
+        config.request_deadline = Some(Duration::from_millis(100));
+        config.route(Method::Get, "/slow", |_request, handler, options| {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                handler.write_status("200 Ok", &[], options).await?;
+                Ok(true)
+            })
+        });
+        let started = Instant::now();
+        let output = exchange(
+            config,
+            b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\nGET / HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(output, "");
+    }
+
+    #[tokio::test]
+    async fn routes_intercept_their_prefix_or_pass_through() {
+        let root = temp_root("routes");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let mut config = Config::new(root);
+        config.route(Method::Post, "/api", |request, handler, options| {
+            Box::pin(async move {
+                let body = format!("{{\"uri\":\"{}\"}}", request.line.uri).into_bytes();
+                handler
+                    .write_buffer("200 Ok", body, "application/json", &[], options)
+                    .await?;
+                Ok(true)
+            })
+        });
+        config.route(Method::Get, "/", |_request, _handler, _options| {
+            Box::pin(async { Ok(false) })
+        });
+        let input = "POST /api/users HTTP/1.1\r\nHost: x\r\n\r\nGET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 2, "{}", output);
+        assert!(responses[0].starts_with("200 Ok\r\n"));
+        assert!(responses[0].ends_with("\r\n\r\n{\"uri\":\"/api/users\"}"));
+        assert!(responses[1].starts_with("200 Ok\r\n") && responses[1].ends_with("\r\n\r\nhello"));
+    }
+
+    #[tokio::test]
+    async fn stalled_request_line_gets_a_408() {
+        let mut config = Config::new(temp_root("read-timeout"));
+        config.read_timeout = Some(Duration::from_millis(100));
+        let state = state_for(config);
+        for (input, expected) in [("GET /a.t", "HTTP/1.0 408 Request Timeout\r\n"), ("", "")] {
+            // The client stays connected without ever finishing the line
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            client.write_all(input.as_bytes()).await.unwrap();
+            let serving = handle_stream(HttpHandler::new(Box::new(server) as Box<dyn Stream>), &state);
+            tokio::time::timeout(Duration::from_secs(5), serving).await.unwrap();
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.starts_with(expected), "{}", output);
+            assert_eq!(output.is_empty(), expected.is_empty(), "{}", output);
+        }
+    }
+
+    #[tokio::test]
+    async fn uris_are_percent_decoded() {
+        let root = temp_root("percent-decode");
+        std::fs::write(root.join("my file é.txt"), "hello").unwrap();
+        let mut config = Config::new(root);
+        config.keep_alive_after_error = true;
+        let input = "GET /my%20file%20%C3%A9.txt HTTP/1.1\r\nHost: x\r\n\r\nGET /a%2 HTTP/1.1\r\nHost: x\r\n\r\nGET /a%zz HTTP/1.1\r\nHost: x\r\n\r\nGET /%2e%2e/etc/passwd HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 4, "{}", output);
+        assert!(responses[0].starts_with("200 Ok\r\n") && responses[0].ends_with("\r\n\r\nhello"));
+        assert!(responses[1].starts_with("400 Bad Request\r\n"));
+        assert!(responses[2].starts_with("400 Bad Request\r\n"));
+        assert!(responses[3].starts_with("403 Forbidden\r\n"));
+    }
+
+    #[tokio::test]
+    async fn query_and_fragment_are_not_part_of_the_path() {
+        let root = temp_root("query");
+        std::fs::write(root.join("index.html"), "hello").unwrap();
+        let input = "GET /index.html?v=123 HTTP/1.1\r\nHost: x\r\n\r\nGET /index.html#top HTTP/1.1\r\nHost: x\r\n\r\nGET /index.html?v=1#top HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(Config::new(root), input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", output);
+        for response in responses {
+            assert!(
+                response.starts_with("200 Ok\r\n") && response.ends_with("\r\n\r\nhello"),
+                "{}",
+                output
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn directories_serve_their_index_or_a_listing() {
+        let root = temp_root("index");
+        std::fs::create_dir(root.join("site")).unwrap();
+        std::fs::write(root.join("site/index.html"), "<h1>site</h1>").unwrap();
+        std::fs::create_dir(root.join("files")).unwrap();
+        std::fs::write(root.join("files/a.txt"), "hello").unwrap();
+        let input = "GET /site HTTP/1.1\r\nHost: x\r\n\r\nGET /site/ HTTP/1.1\r\nHost: x\r\n\r\nGET /files/ HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(Config::new(root), input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", output);
+        assert!(responses[0].starts_with("301 Moved Permanently\r\n"));
+        assert_eq!(header(responses[0], "Location"), Some("/site/"));
+        assert!(responses[1].starts_with("200 Ok\r\n") && responses[1].ends_with("\r\n\r\n<h1>site</h1>"));
+        assert!(responses[2].starts_with("200 Ok\r\n") && responses[2].contains("href=\"a.txt\""));
+    }
+
+    #[tokio::test]
+    async fn ranges_serve_partial_content() {
+        let root = temp_root("ranges");
+        std::fs::write(root.join("a.txt"), "0123456789").unwrap();
+        let cases = [
+            ("bytes=2-4", "206 Partial Content", Some("bytes 2-4/10"), "234"),
+            ("bytes=7-", "206 Partial Content", Some("bytes 7-9/10"), "789"),
+            ("bytes=-3", "206 Partial Content", Some("bytes 7-9/10"), "789"),
+            ("bytes=-20", "206 Partial Content", Some("bytes 0-9/10"), "0123456789"),
+            ("bytes=5-100", "206 Partial Content", Some("bytes 5-9/10"), "56789"),
+            ("bytes=99999999999999999999999-", "200 Ok", None, "0123456789"),
+            (
+                "bytes=10-",
+                "416 Range Not Satisfiable",
+                Some("bytes */10"),
+                "416 Range Not Satisfiable",
+            ),
+            (
+                "bytes=-0",
+                "416 Range Not Satisfiable",
+                Some("bytes */10"),
+                "416 Range Not Satisfiable",
+            ),
+        ];
+        for (range, status, content_range, body) in cases {
+            let input = format!("GET /a.txt HTTP/1.1\r\nHost: x\r\nRange: {}\r\n\r\n", range);
+            let output = exchange(Config::new(root.clone()), input.as_bytes()).await;
+            assert!(
+                output.starts_with(&format!("HTTP/1.1 {}\r\n", status)),
+                "{}: {}",
+                range,
+                output
+            );
+            assert_eq!(header(&output, "Content-Range"), content_range, "{}", range);
+            assert_eq!(header(&output, "Content-Length"), Some(body.len().to_string().as_str()));
+            assert!(output.ends_with(&format!("\r\n\r\n{}", body)), "{}: {}", range, output);
+            if status != "416 Range Not Satisfiable" {
+                assert_eq!(header(&output, "Accept-Ranges"), Some("bytes"), "{}", range);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_rejected() {
+        let root = temp_root("size-limits");
+        let long_uri = format!("GET /{} HTTP/1.1\r\nHost: x\r\n\r\n", "a".repeat(100));
+        let long_header = format!("GET / HTTP/1.1\r\nHost: x\r\nX-Big: {}\r\n\r\n", "a".repeat(100));
+        for (input, status) in [
+            (long_uri, "414 URI Too Long"),
+            (long_header, "431 Request Header Fields Too Large"),
+        ] {
+            let mut config = Config::new(root.clone());
+            config.max_request_line = 64;
+            config.max_headers_size = 64;
+            let output = exchange(config, input.repeat(2).as_bytes()).await;
+            assert!(output.starts_with(&format!("HTTP/1.0 {}\r\n", status)), "{}", output);
+            assert_eq!(header(&output, "Connection"), Some("close"));
+            assert_eq!(output.matches("HTTP/1.").count(), 1, "{}", output);
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_fire_in_order() {
+        #[derive(Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl Hooks for Recorder {
+            fn connection_opened(&self, _peer_addr: Option<SocketAddr>) {
+                self.0.lock().unwrap().push("opened".to_string());
+            }
+            fn request_received(&self, _peer_addr: Option<SocketAddr>, request: &Request) {
+                self.0.lock().unwrap().push(format!("request {}", request.line.uri));
+            }
+            fn response_sent(
+                &self,
+                _peer_addr: Option<SocketAddr>,
+                request: Option<&Request>,
+                status: u16,
+                bytes: u64,
+                _elapsed: Duration,
+            ) {
+                let uri = request.map_or("-", |v| v.line.uri.as_str());
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("response {} {} {}", uri, status, bytes));
+            }
+            fn connection_closed(&self, _peer_addr: Option<SocketAddr>, _elapsed: Duration) {
+                self.0.lock().unwrap().push("closed".to_string());
+            }
+        }
+
+        let root = temp_root("hooks");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let recorder = Recorder::default();
+        let events = recorder.0.clone();
+        let mut config = Config::new(root);
+        config.hooks = Some(Box::new(recorder));
+        let input = "GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\nGET /missing HTTP/1.1\r\nHost: x\r\n\r\n";
+        exchange(config, input.as_bytes()).await;
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "opened",
+                "request /a.txt",
+                "response /a.txt 200 5",
+                "request /missing",
+                "response /missing 404 13",
+                "closed",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn put_creates_or_replaces_files() {
+        let root = temp_root("upload");
+        let mut config = Config::new(root.clone());
+        config.enable_upload = true;
+        let input = "PUT /dir/sub/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloPUT /dir/sub/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nbye";
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 2, "{}", output);
+        assert!(responses[0].starts_with("201 Created\r\n"));
+        assert_eq!(header(responses[0], "Location"), Some("/dir/sub/a.txt"));
+        assert!(responses[1].starts_with("204 No Content\r\n"));
+        assert_eq!(std::fs::read_to_string(root.join("dir/sub/a.txt")).unwrap(), "bye");
+        assert_eq!(std::fs::read_dir(root.join("dir/sub")).unwrap().count(), 1);
+
+        let output = exchange(
+            Config::new(root.clone()),
+            b"PUT /b.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(!root.join("b.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn concurrent_uploads_write_separate_temporary_files() {
+        let root = temp_root("upload-concurrent");
+        let mut config = Config::new(root.clone());
+        config.enable_upload = true;
+        let state = state_for(config);
+        let head = "PUT /a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 8\r\n\r\n";
+        let (mut first, first_server) = tokio::io::duplex(64 * 1024);
+        let (mut second, second_server) = tokio::io::duplex(64 * 1024);
+        first.write_all(format!("{}aaaa", head).as_bytes()).await.unwrap();
+        second.write_all(format!("{}bbbb", head).as_bytes()).await.unwrap();
+        let parts = || {
+            std::fs::read_dir(&root)
+                .unwrap()
+                .filter(|v| v.as_ref().unwrap().file_name().to_string_lossy().ends_with(".part"))
+                .count()
+        };
+        let clients = async {
+            // Both uploads are halfway through, each must have its own file
+            let started = Instant::now();
+            while parts() < 2 {
+                assert!(started.elapsed() < Duration::from_secs(5));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            first.write_all(b"aaaa").await.unwrap();
+            second.write_all(b"bbbb").await.unwrap();
+            first.shutdown().await.unwrap();
+            second.shutdown().await.unwrap();
+        };
+        tokio::join!(
+            handle_stream(HttpHandler::new(Box::new(first_server) as Box<dyn Stream>), &state),
+            handle_stream(HttpHandler::new(Box::new(second_server) as Box<dyn Stream>), &state),
+            clients,
+        );
+        let body = std::fs::read_to_string(root.join("a.txt")).unwrap();
+        assert!(body == "aaaaaaaa" || body == "bbbbbbbb", "{}", body);
+        assert_eq!(parts(), 0);
+    }
+
+    #[tokio::test]
+    async fn delete_removes_uploaded_files() {
+        let root = temp_root("delete");
+        let mut config = Config::new(root.clone());
+        config.enable_upload = true;
+        config.keep_alive_after_error = true;
+        let input = "PUT /a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloDELETE /a.txt HTTP/1.1\r\nHost: x\r\n\r\nDELETE /a.txt HTTP/1.1\r\nHost: x\r\n\r\nDELETE /../a.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 4, "{}", output);
+        assert!(responses[0].starts_with("201 Created\r\n"));
+        assert!(responses[1].starts_with("204 No Content\r\n"));
+        assert!(responses[2].starts_with("404 Not Found\r\n"));
+        assert!(responses[3].starts_with("403 Forbidden\r\n"));
+        assert!(!root.join("a.txt").exists());
+
+        std::fs::write(root.join("b.txt"), "hello").unwrap();
+        let output = exchange(Config::new(root.clone()), b"DELETE /b.txt HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(output.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(root.join("b.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn escaping_root_is_forbidden_rather_than_missing() {
+        let root = temp_root("escape");
+        let outside = temp_root("escape-outside");
+        std::fs::write(outside.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("link.txt")).unwrap();
+        let mut config = Config::new(root);
+        config.keep_alive_after_error = true;
+        let input = format!(
+            "GET /../{}/secret.txt HTTP/1.1\r\nHost: x\r\n\r\nGET /link.txt HTTP/1.1\r\nHost: x\r\n\r\nGET /missing.txt HTTP/1.1\r\nHost: x\r\n\r\n",
+            outside.file_name().unwrap().to_string_lossy()
+        );
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", output);
+        assert!(responses[0].starts_with("403 Forbidden\r\n"));
+        assert!(responses[1].starts_with("403 Forbidden\r\n"));
+        assert!(responses[2].starts_with("404 Not Found\r\n"));
+        assert!(!output.contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_limit_wait_for_a_permit() {
+        let root = temp_root("max-connections");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let listener = bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, _wg) = mpsc::channel::<()>(1);
+        let state = Arc::new(state_for(Config::new(root)));
+        let accepting = tokio::spawn(accept_loop(listener, state, None, Arc::new(Semaphore::new(1)), sender));
+        let request = "GET /a.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+
+        let mut first = tokio::net::TcpStream::connect(address).await.unwrap();
+        first.write_all(&request.as_bytes()[..10]).await.unwrap();
+        let mut second = tokio::net::TcpStream::connect(address).await.unwrap();
+        second.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        let waiting = tokio::time::timeout(Duration::from_millis(200), second.read_to_end(&mut response)).await;
+        assert!(waiting.is_err() && response.is_empty());
+
+        first.write_all(&request.as_bytes()[10..]).await.unwrap();
+        let mut output = Vec::new();
+        first.read_to_end(&mut output).await.unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 200 Ok\r\n"));
+        let mut output = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), second.read_to_end(&mut output))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 200 Ok\r\n"));
+        accepting.abort();
+    }
+
+    #[tokio::test]
+    async fn not_found_uses_the_custom_page_when_present() {
+        let root = temp_root("custom-404");
+        let output = exchange(Config::new(root.clone()), b"GET /missing HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert_eq!(header(&output, "Content-Type"), Some("text/plain; charset=utf-8"));
+        assert!(output.ends_with("\r\n\r\n404 Not Found"));
+
+        std::fs::write(root.join("404.html"), "<h1>lost</h1>").unwrap();
+        let mut config = Config::new(root);
+        config.keep_alive_after_error = true;
+        let input = "GET /missing HTTP/1.1\r\nHost: x\r\n\r\nHEAD /missing HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 2, "{}", output);
+        for response in &responses {
+            assert!(response.starts_with("404 Not Found\r\n"));
+            assert_eq!(header(response, "Content-Type"), Some("text/html; charset=utf-8"));
+            assert_eq!(header(response, "Content-Length"), Some("13"));
+        }
+        assert!(responses[0].ends_with("\r\n\r\n<h1>lost</h1>"));
+        assert!(responses[1].ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn options_allow_reflects_write_mode() {
+        let root = temp_root("options");
+        let input = "OPTIONS * HTTP/1.1\r\nHost: x\r\n\r\nOPTIONS /a.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        for (enable_upload, allow) in [(false, "GET, HEAD, OPTIONS"), (true, "GET, HEAD, OPTIONS, PUT, DELETE")] {
+            let mut config = Config::new(root.clone());
+            config.enable_upload = enable_upload;
+            let output = exchange(config, input.as_bytes()).await;
+            let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+            assert_eq!(responses.len(), 2, "{}", output);
+            for response in responses {
+                assert!(response.starts_with("204 No Content\r\n"));
+                assert_eq!(header(response, "Allow"), Some(allow));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn once_stops_after_the_first_served_connection() {
+        let root = temp_root("once");
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let listener = bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, mut wg) = mpsc::channel::<()>(1);
+        let mut config = Config::new(root);
+        config.once = true;
+        let state = Arc::new(state_for(config));
+        let accepting = tokio::spawn(accept_loop(listener, state, None, Arc::new(Semaphore::new(1)), sender));
+
+        // Leaving without sending anything doesn't use up the one connection
+        drop(tokio::net::TcpStream::connect(address).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!accepting.is_finished());
+
+        let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\nGET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("HTTP/1.1 200 Ok\r\n").count(), 1, "{}", output);
+        assert_eq!(header(&output, "Connection"), Some("close"));
+        tokio::time::timeout(Duration::from_secs(5), accepting)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(wg.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn control_bytes_in_the_uri_get_a_400() {
+        let root = temp_root("control-bytes");
+        std::fs::write(root.join("ab"), "hello").unwrap();
+        for input in [
+            &b"GET /a\x00b HTTP/1.1\r\nHost: x\r\n\r\n"[..],
+            b"GET /a\x01b HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"GET /a\x7fb HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"GET /a\rb HTTP/1.1\r\nHost: x\r\n\r\n",
+        ] {
+            let output = exchange(Config::new(root.clone()), input).await;
+            assert!(output.contains(" 400 Bad Request\r\n"), "{}", output);
+            assert!(output.ends_with("\r\n\r\n400 Bad Request"), "{}", output);
+        }
+    }
+
+    #[tokio::test]
+    async fn excluded_files_are_invisible_and_unfetchable() {
+        let root = temp_root("exclude");
+        std::fs::write(root.join("server.key"), "key").unwrap();
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        std::fs::create_dir_all(root.join("nested/secrets")).unwrap();
+        std::fs::write(root.join("nested/deep.key"), "key").unwrap();
+        std::fs::write(root.join("nested/secrets/token"), "token").unwrap();
+        std::fs::write(root.join(".httpfsignore"), "# from the ignore file\nsecrets/\n").unwrap();
+        let mut config = Config::new(root);
+        config.exclude = vec!["*.key".to_string()];
+        config.keep_alive_after_error = true;
+        let input = "GET / HTTP/1.1\r\nHost: x\r\n\r\nGET /nested/ HTTP/1.1\r\nHost: x\r\n\r\nGET /server.key HTTP/1.1\r\nHost: x\r\n\r\nGET /nested/deep.key HTTP/1.1\r\nHost: x\r\n\r\nGET /nested/secrets/token HTTP/1.1\r\nHost: x\r\n\r\nGET /.httpfsignore HTTP/1.1\r\nHost: x\r\n\r\n";
+        let output = exchange(config, input.as_bytes()).await;
+        let responses: Vec<_> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 6, "{}", output);
+        assert!(responses[0].starts_with("200 Ok\r\n") && responses[0].contains("a.txt"));
+        assert!(!responses[0].contains("server.key") && !responses[0].contains(".httpfsignore"));
+        assert!(responses[1].starts_with("200 Ok\r\n"));
+        assert!(!responses[1].contains("deep.key") && !responses[1].contains("secrets"));
+        for response in &responses[2..] {
+            assert!(response.starts_with("404 Not Found\r\n"), "{}", output);
+        }
+    }
 }
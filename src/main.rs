@@ -1,22 +1,202 @@
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
     time::Duration,
 };
 
 use clap::Parser;
-use tokio::select;
+use mime_guess::Mime;
 use tokio_util::sync::CancellationToken;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, default_value_t = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))]
-    address: IpAddr,
+    /// Address to listen on, can be repeated to listen on several
+    #[arg(short, long, default_values_t = [IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))])]
+    address: Vec<IpAddr>,
     #[arg(short, long, default_value_t = 8000)]
     port: u16,
     #[arg(short, long, value_hint = clap::ValueHint::DirPath)]
     directory: Option<PathBuf>,
+    /// Only serve files with these extensions (comma separated or repeated)
+    #[arg(long, value_delimiter = ',')]
+    allow_ext: Vec<String>,
+    /// Only list the root directory, subdirectories return 403
+    #[arg(long)]
+    list_root_only: bool,
+    /// Minify served JSON files, `?pretty=1` pretty-prints them instead
+    #[arg(long)]
+    minify_json: bool,
+    /// Keep connections open after responding with an error status
+    #[arg(long)]
+    keep_alive_after_error: bool,
+    /// How to handle a trailing slash on a file path
+    #[arg(long, value_enum, default_value_t)]
+    trailing_slash_policy: http_rust::TrailingSlashPolicy,
+    /// Switch to this user after binding the socket (Unix only)
+    #[arg(long)]
+    user: Option<String>,
+    /// Switch to this group after binding the socket (Unix only)
+    #[arg(long)]
+    group: Option<String>,
+    /// Chroot into the served directory after binding the socket (Unix only)
+    #[arg(long)]
+    chroot: bool,
+    /// Detect the content type of files with unknown extensions from their contents
+    #[arg(long)]
+    magic_sniff: bool,
+    /// Never send this response header, can be repeated
+    #[arg(long = "strip-header", value_name = "NAME")]
+    strip_headers: Vec<String>,
+    /// Debugging aid: wait this many milliseconds before sending each response
+    #[arg(long, value_name = "MS")]
+    response_delay: Option<u64>,
+    /// Close every connection after one response
+    #[arg(long)]
+    disable_keepalive: bool,
+    /// Serve `/about` from `about.html` when there's no `about` file
+    #[arg(long, alias = "clean-urls")]
+    try_html_extension: bool,
+    /// Abort requests that take longer than this many seconds from their first byte to the end of the response
+    #[arg(long, value_name = "SECS")]
+    request_deadline: Option<u64>,
+    /// Show a breadcrumb trail at the top of directory listings
+    #[arg(long)]
+    listing_breadcrumbs: bool,
+    /// Send a 103 Early Hints response with this Link before HTML files, e.g. '</app.css>; rel=preload; as=style'
+    #[arg(long = "early-hint-link", value_name = "LINK")]
+    early_hint_links: Vec<String>,
+    /// Seconds to wait for a request before closing the connection, 0 waits forever
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    timeout: u64,
+    /// Serve this file instead of the listing when a directory contains it, can be repeated
+    #[arg(long = "index-file", value_name = "NAME", default_value = "index.html")]
+    index_files: Vec<String>,
+    /// Largest response body, in bytes, to build in memory. Larger listings fail and larger JSON isn't reformatted
+    #[arg(long, value_name = "BYTES")]
+    max_memory_response: Option<usize>,
+    /// Serve another file for `/` when the User-Agent matches, as 'name:regex=/path'. Can be repeated
+    #[arg(long = "ua-rule", value_name = "RULE")]
+    ua_rules: Vec<http_rust::UaRule>,
+    /// Log requests that take at least this many milliseconds, with a timing breakdown
+    #[arg(long, value_name = "MS")]
+    log_slow: Option<u64>,
+    /// Never compress responses
+    #[arg(long)]
+    no_compression: bool,
+    /// Files smaller than this are never compressed
+    #[arg(long, value_name = "BYTES", default_value_t = 1024)]
+    compress_min_size: usize,
+    /// Never compress files with these extensions (comma separated or repeated)
+    #[arg(long, value_delimiter = ',', value_name = "EXTS")]
+    no_compress_ext: Vec<String>,
+    /// Only compress responses over TLS, against BREACH style attacks on plain connections
+    #[arg(long, requires = "tls_cert")]
+    compress_tls_only: bool,
+    /// Contents of `/robots.txt` when the directory has none, `\n` gets replaced by a newline
+    #[arg(long, value_name = "CONTENT")]
+    robots: Option<String>,
+    /// Generate `/sitemap.xml` listing the HTML files when the directory has none
+    #[arg(long)]
+    generate_sitemap: bool,
+    /// Reject request paths with more segments than this
+    #[arg(long, value_name = "N")]
+    max_path_depth: Option<usize>,
+    /// Longest request line accepted, longer ones get 414
+    #[arg(long, value_name = "BYTES", default_value_t = 8 * 1024)]
+    max_request_line: usize,
+    /// Largest header section accepted, larger ones get 431
+    #[arg(long, value_name = "BYTES", default_value_t = 8 * 1024)]
+    max_header_size: usize,
+    /// Accept HTTP/0.9 requests, a request line without a version, and answer them with only the body
+    #[arg(long)]
+    allow_http09: bool,
+    /// Connections served at once, further clients wait until one closes (0 for no limit)
+    #[arg(long, value_name = "N", default_value_t = 1024)]
+    max_connections: usize,
+    /// Body bytes per second sent over each connection (0 for no limit)
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    rate_limit: u64,
+    /// Bytes of small files kept in memory between requests (0 disables the cache)
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    cache_size: usize,
+    /// Largest file, in bytes, put in the cache
+    #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024)]
+    cache_file_size: usize,
+    /// Access log format, printed to stdout
+    #[arg(long, value_enum, default_value_t)]
+    log_format: http_rust::LogFormat,
+    /// Only print startup and errors, no access log
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print request lines, -vv adds connections and resolved paths
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Let clients cache files for this many seconds, 0 makes them revalidate every time
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    cache_max_age: u64,
+    /// Load extra extension mappings from a mime.types file, they take precedence over the built-in ones
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    mime_types: Option<PathBuf>,
+    /// Serve files with this extension as this type, such as '.mjs=text/javascript'. Can be repeated, takes precedence
+    /// over --mime-types
+    #[arg(long, value_name = "EXT=TYPE", value_parser = parse_mime)]
+    mime: Vec<(String, Mime)>,
+    /// Allow cross-origin requests from these origins (comma separated), or from any with '*'
+    #[arg(long, value_delimiter = ',', value_name = "ORIGINS")]
+    cors: Vec<String>,
+    /// Response headers cross-origin scripts may read (comma separated), such as ETag or Content-Range
+    #[arg(long, value_delimiter = ',', value_name = "HEADERS", requires = "cors")]
+    cors_expose_headers: Vec<String>,
+    /// Reject malformed or ambiguous requests, such as repeated Content-Length headers, with 400
+    #[arg(long, alias = "http1-only")]
+    strict: bool,
+    /// Require these credentials through HTTP Basic authentication
+    #[arg(long, value_name = "USER:PASSWORD")]
+    auth: Option<String>,
+    /// Serve over HTTPS with this PEM certificate chain, requires --tls-key
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Seconds to wait for open connections to finish after Ctrl-C, before giving up on them
+    #[arg(long, value_name = "SECS", default_value_t = 5)]
+    shutdown_timeout: u64,
+    /// Serve a single request and exit
+    #[arg(long)]
+    once: bool,
+    /// Path prefix the server is mounted under, such as /files behind a reverse proxy
+    #[arg(long, value_name = "PATH")]
+    base_path: Option<String>,
+    /// Value of the Server header, empty to not send one
+    #[arg(long, value_name = "VALUE")]
+    server_header: Option<String>,
+    /// Canned response for `/`, either 'text:<body>' or 'status:<code>'
+    #[arg(long, value_name = "RESPONSE")]
+    root_response: Option<http_rust::RootResponse>,
+    /// Accept PUT and DELETE requests to write and remove files under the served directory
+    #[arg(long, alias = "enable-write")]
+    enable_upload: bool,
+    /// Hide dotfiles, such as .git or .env, from listings and refuse to serve them
+    #[arg(long)]
+    no_dotfiles: bool,
+    /// Hide paths matching this glob, relative to the directory, from listings and requests. Can be repeated, a
+    /// .httpfsignore file in the directory adds more
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Largest upload accepted, in bytes, larger ones get 413
+    #[arg(long, value_name = "BYTES", requires = "enable_upload")]
+    max_upload_size: Option<u64>,
+    /// Largest request body, in bytes, read just to be discarded. Larger ones get 413 unless they are uploads
+    #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024)]
+    discard_limit: u64,
+    /// Serve the root index file for page navigations to missing paths, for single page apps
+    #[arg(long)]
+    spa: bool,
+    /// Print the effective configuration and exit without starting the server
+    #[arg(long)]
+    print_config: bool,
 }
 
 #[tokio::main]
@@ -27,31 +207,114 @@ async fn main() {
         .unwrap_or_else(|| PathBuf::from("."))
         .canonicalize()
         .unwrap();
-    let address = format!("{}:{}", args.address, args.port);
+    let mut config = http_rust::Config::new(root);
+    config.addresses = args
+        .address
+        .iter()
+        .map(|v| SocketAddr::new(*v, args.port).to_string())
+        .collect();
+    config.allow_ext = args.allow_ext;
+    config.list_root_only = args.list_root_only;
+    config.minify_json = args.minify_json;
+    config.keep_alive_after_error = args.keep_alive_after_error;
+    config.trailing_slash_policy = args.trailing_slash_policy;
+    config.user = args.user;
+    config.group = args.group;
+    config.chroot = args.chroot;
+    config.magic_sniff = args.magic_sniff;
+    config.strip_headers = args.strip_headers;
+    config.response_delay = args.response_delay.map(Duration::from_millis);
+    config.disable_keepalive = args.disable_keepalive;
+    config.try_html_extension = args.try_html_extension;
+    config.request_deadline = args.request_deadline.map(Duration::from_secs);
+    config.listing_breadcrumbs = args.listing_breadcrumbs;
+    config.early_hint_links = args.early_hint_links;
+    config.index_files = args.index_files;
+    config.read_timeout = (args.timeout > 0).then(|| Duration::from_secs(args.timeout));
+    config.max_memory_response = args.max_memory_response;
+    config.ua_rules = args.ua_rules;
+    config.log_slow = args.log_slow.map(Duration::from_millis);
+    config.disable_compression = args.no_compression;
+    config.compress_min_size = args.compress_min_size;
+    config.no_compress_ext = args.no_compress_ext;
+    config.compress_tls_only = args.compress_tls_only;
+    config.robots = args.robots.map(|v| v.replace("\\n", "\n"));
+    config.generate_sitemap = args.generate_sitemap;
+    config.max_path_depth = args.max_path_depth;
+    config.max_request_line = args.max_request_line;
+    config.max_headers_size = args.max_header_size;
+    config.allow_http09 = args.allow_http09;
+    config.max_connections = args.max_connections;
+    config.rate_limit = args.rate_limit;
+    config.cache_size = args.cache_size;
+    config.cache_file_size = args.cache_file_size;
+    config.log_format = args.log_format;
+    config.verbosity = match (args.quiet, args.verbose) {
+        (true, _) => http_rust::Verbosity::Quiet,
+        (false, 0) => http_rust::Verbosity::Normal,
+        (false, 1) => http_rust::Verbosity::Verbose,
+        (false, _) => http_rust::Verbosity::Debug,
+    };
+    config.cache_max_age = args.cache_max_age;
+    config.cors = args.cors;
+    config.cors_expose_headers = args.cors_expose_headers;
+    config.strict = args.strict;
+    config.auth = args.auth;
+    let scheme = if args.tls_cert.is_some() { "https" } else { "http" };
+    config.tls_cert = args.tls_cert;
+    config.tls_key = args.tls_key;
+    config.shutdown_timeout = Some(Duration::from_secs(args.shutdown_timeout));
+    config.once = args.once;
+    if let Some(server) = args.server_header {
+        config.server_header = (!server.is_empty()).then_some(server);
+    }
+    if let Some(base) = args.base_path {
+        config.base_path = format!("/{}", base.trim_matches('/'));
+    }
+    config.root_response = args.root_response;
+    config.enable_upload = args.enable_upload;
+    config.no_dotfiles = args.no_dotfiles;
+    config.exclude = args.exclude;
+    config.max_upload_size = args.max_upload_size;
+    config.discard_limit = args.discard_limit;
+    config.spa = args.spa;
+    if let Some(path) = &args.mime_types {
+        if let Err(err) = config.load_mime_types(path) {
+            eprintln!("failed to load {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+    for (ext, mime) in args.mime {
+        config.mime_types.insert(ext, mime);
+    }
+    if args.print_config {
+        print!("{}", config);
+        return;
+    }
 
     let cancel = CancellationToken::new();
     let cancel_sig = cancel.clone();
-    let mut run_handle = tokio::spawn(async move {
-        println!("http://{}", address);
-        http_rust::run(&address, root, cancel_sig).await.unwrap()
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel_sig.cancel();
+        }
     });
+    for address in &config.addresses {
+        println!("{}://{}", scheme, address);
+    }
+    if let Err(err) = http_rust::serve(config, cancel).await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
 
-    select! {
-        r = &mut run_handle => {
-            r.unwrap();
-            return;
-        },
-        _ = tokio::signal::ctrl_c() => {
-            cancel.cancel();
-        }
-    };
-    select! {
-        r = &mut run_handle => {
-            r.unwrap();
-            return;
-        }
-        _ = tokio::time::sleep(Duration::from_secs(5)) => {
-            panic!("forced shutdown after timeout");
-        },
-    };
+// `.ext=type`, the dot is optional
+fn parse_mime(s: &str) -> Result<(String, Mime), String> {
+    let (ext, mime) = s.split_once('=').ok_or("expected EXT=TYPE")?;
+    let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+    if ext.is_empty() {
+        return Err("missing extension".to_string());
+    }
+    let mime = mime.parse().map_err(|_| format!("invalid type '{}'", mime))?;
+    Ok((ext, mime))
 }
@@ -0,0 +1,73 @@
+use std::ffi::CString;
+use std::io::{self, Error};
+use std::path::PathBuf;
+
+use crate::Config;
+
+/// Chroots and switches to the configured user/group, returning the config with the root adjusted for the chroot.
+///
+/// Any failure is returned as an error, the caller must not keep serving if this fails.
+pub fn drop_privileges(mut config: Config) -> io::Result<Config> {
+    if config.user.is_none() && config.group.is_none() && !config.chroot {
+        return Ok(config);
+    }
+    // Look up the ids before chrooting, since the user database probably isn't available inside it
+    let (uid, mut gid) = match &config.user {
+        Some(user) => {
+            let (uid, gid) = lookup_user(user)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    if let Some(group) = &config.group {
+        gid = Some(lookup_group(group)?);
+    }
+
+    if config.chroot {
+        let root = CString::new(config.root.as_os_str().as_encoded_bytes()).map_err(Error::other)?;
+        check(unsafe { libc::chroot(root.as_ptr()) }, "chroot")?;
+        check(unsafe { libc::chdir(c"/".as_ptr()) }, "chdir")?;
+        config.root = PathBuf::from("/");
+    }
+    if let Some(gid) = gid {
+        // Only root can drop supplementary groups, and it must happen before giving up root
+        if unsafe { libc::geteuid() } == 0 {
+            check(unsafe { libc::setgroups(1, &gid) }, "setgroups")?;
+        }
+        check(unsafe { libc::setgid(gid) }, "setgid")?;
+    }
+    if let Some(uid) = uid {
+        check(unsafe { libc::setuid(uid) }, "setuid")?;
+        // Make sure the drop is permanent before we accept any connection
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(Error::other("privileges could be regained after setuid"));
+        }
+    }
+    Ok(config)
+}
+
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let cname = CString::new(name).map_err(Error::other)?;
+    let pw = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if pw.is_null() {
+        return Err(Error::other(format!("unknown user '{}'", name)));
+    }
+    Ok(unsafe { ((*pw).pw_uid, (*pw).pw_gid) })
+}
+
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let cname = CString::new(name).map_err(Error::other)?;
+    let gr = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if gr.is_null() {
+        return Err(Error::other(format!("unknown group '{}'", name)));
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+fn check(ret: libc::c_int, call: &str) -> io::Result<()> {
+    if ret != 0 {
+        let err = Error::last_os_error();
+        return Err(Error::new(err.kind(), format!("{} failed: {}", call, err)));
+    }
+    Ok(())
+}